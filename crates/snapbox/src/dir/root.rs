@@ -95,6 +95,21 @@ impl DirRoot {
             DirRootInner::MutableTemp { temp, .. } => temp.close(),
         }
     }
+
+    /// Close without deleting a temporary directory, returning where it is
+    pub fn keep(self) -> Option<std::path::PathBuf> {
+        match self.0 {
+            DirRootInner::None => None,
+            DirRootInner::Immutable(path) => Some(path),
+            #[cfg(feature = "dir")]
+            DirRootInner::MutablePath(path) => Some(path),
+            #[cfg(feature = "dir")]
+            DirRootInner::MutableTemp { temp, path } => {
+                let _ = temp.into_path();
+                Some(path)
+            }
+        }
+    }
 }
 
 impl Default for DirRoot {
@@ -12,9 +12,38 @@ fn run() -> Result<(), Box<dyn Error>> {
         eprintln!("{}", text);
     }
 
+    if let Ok(names) = env::var("echo_env") {
+        for name in names.split(',') {
+            match env::var(name) {
+                Ok(value) => println!("{}={}", name, value),
+                Err(_) => println!("{} unset", name),
+            }
+        }
+    }
+
+    if let Ok(cmdline) = env::var("spawn") {
+        let mut words = cmdline.split_whitespace();
+        if let Some(program) = words.next() {
+            let status = process::Command::new(program).args(words).status()?;
+            if !status.success() {
+                eprintln!("{} failed: {}", program, status);
+            }
+        }
+    }
+
+    if env::var("echo_stdin").as_deref() == Ok("1") {
+        io::copy(&mut io::stdin(), &mut io::stdout())?;
+    }
+
     if env::var("echo_large").as_deref() == Ok("1") {
+        let mut stdout = io::stdout().lock();
         for i in 0..(128 * 1024) {
-            println!("{}", i);
+            match writeln!(stdout, "{}", i) {
+                Ok(()) => {}
+                // The reader stopped early, like `head`
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => break,
+                Err(e) => return Err(e.into()),
+            }
         }
     }
 
@@ -0,0 +1,363 @@
+//! Run `trycmd` test cases outside of a `#[test]` harness
+
+use std::io::prelude::*;
+
+const USAGE: &str = "\
+Run trycmd test cases
+
+Usage: trycmd [OPTIONS] <CASE>... [trycmd=<FILTER>...]
+       trycmd record [OPTIONS] <CASE> -- <BIN> [ARGS]...
+       trycmd convert <SOURCE> <TARGET>
+
+Arguments:
+  <CASE>...          Test case files or globs, like `tests/cmd/*.toml`
+  trycmd=<FILTER>    Only run cases whose path contains FILTER
+
+Options:
+      --bin-name <NAME>          Default bin, by name, for commands
+      --bin-path <PATH>          Default bin, by path, for commands
+      --register <NAME[=PATH]>   Add a bin to the \"PATH\" for cases to use,
+                                 looking it up in `PATH` if no path is given
+      --env <KEY=VALUE>          Set default environment variable
+      --timeout <DURATION>       Set default timeout for commands, like `10s`
+      --hermetic                 Run commands in a hermetic environment
+      --exclude <GLOB>           Don't run cases matching GLOB
+      --shard <INDEX/TOTAL>      Only run shard INDEX, starting from 1, of TOTAL
+      --mode <MODE>              `fail` (default), `overwrite`, or `dump`
+  -h, --help                     Print help
+
+Record Options:
+      --bin-path <PATH>          Path to run for BIN, looking it up in `PATH` by default
+      --env <KEY=VALUE>          Set environment variable
+      --in <DIR>                 Directory to run BIN in, copied to the case's `*.in/`
+      --timeout <DURATION>       Set timeout for the command, like `10s`
+";
+
+fn main() {
+    let mut raw = std::env::args_os().skip(1).peekable();
+    let res = if raw.peek().map(|a| a == "record").unwrap_or(false) {
+        raw.next();
+        RecordArgs::parse(raw).map(|args| args.map(record))
+    } else if raw.peek().map(|a| a == "convert").unwrap_or(false) {
+        raw.next();
+        ConvertArgs::parse(raw).map(|args| args.map(convert))
+    } else {
+        Args::parse(raw).map(|args| args.map(run))
+    };
+    match res {
+        Ok(Some(code)) => std::process::exit(code),
+        Ok(None) => {
+            let _ = std::io::stdout().write_all(USAGE.as_bytes());
+        }
+        Err(err) => {
+            let _ = writeln!(std::io::stderr(), "error: {err}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    }
+}
+
+fn run(args: Args) -> i32 {
+    let t = trycmd::TestCases::new();
+    if let Some((index, total)) = args.shard {
+        t.shard(index, total);
+    }
+    if let Some(mode) = &args.mode {
+        t.mode(mode);
+    }
+    if let Some(name) = &args.bin_name {
+        t.default_bin_name(name);
+    }
+    if let Some(path) = &args.bin_path {
+        t.default_bin_path(path);
+    }
+    t.register_bins(args.bins);
+    for (key, value) in args.env {
+        t.env(key, value);
+    }
+    if let Some(timeout) = args.timeout {
+        t.timeout(timeout);
+    }
+    if args.hermetic {
+        t.hermetic(true);
+    }
+    for case in &args.cases {
+        t.case(case);
+    }
+    for glob in &args.exclude {
+        t.exclude(glob);
+    }
+
+    match t.try_run() {
+        Ok(()) => 0,
+        Err(summary) => {
+            // Failures were reported as they happened
+            let _ = writeln!(std::io::stderr(), "{summary}");
+            1
+        }
+    }
+}
+
+fn record(args: RecordArgs) -> i32 {
+    let mut recording = trycmd::Recording::new(&args.bin).args(args.args);
+    if let Some(path) = args.bin_path.or_else(|| find_in_path(&args.bin)) {
+        recording = recording.bin_path(path);
+    }
+    for (key, value) in args.env {
+        recording = recording.env(key, value);
+    }
+    if let Some(input) = args.input {
+        recording = recording.input_dir(input);
+    }
+    if let Some(timeout) = args.timeout {
+        recording = recording.timeout(timeout);
+    }
+
+    match recording.write(&args.case) {
+        Ok(()) => 0,
+        Err(err) => {
+            let _ = writeln!(
+                std::io::stderr(),
+                "error: failed to record {}: {err}",
+                args.case.display()
+            );
+            1
+        }
+    }
+}
+
+fn convert(args: ConvertArgs) -> i32 {
+    match trycmd::convert(&args.source, &args.target) {
+        Ok(warnings) => {
+            for warning in warnings {
+                let _ = writeln!(std::io::stderr(), "warning: {warning}");
+            }
+            0
+        }
+        Err(err) => {
+            let _ = writeln!(
+                std::io::stderr(),
+                "error: failed to convert {}: {err}",
+                args.source.display()
+            );
+            1
+        }
+    }
+}
+
+#[derive(Default)]
+struct Args {
+    cases: Vec<std::path::PathBuf>,
+    bin_name: Option<String>,
+    bin_path: Option<std::path::PathBuf>,
+    bins: Vec<(String, trycmd::schema::Bin)>,
+    env: Vec<(String, String)>,
+    timeout: Option<std::time::Duration>,
+    hermetic: bool,
+    exclude: Vec<std::path::PathBuf>,
+    shard: Option<(usize, usize)>,
+    mode: Option<String>,
+}
+
+impl Args {
+    fn parse(raw: impl IntoIterator<Item = std::ffi::OsString>) -> Result<Option<Self>, String> {
+        let mut args = Self::default();
+
+        let mut raw = raw.into_iter();
+        while let Some(arg) = raw.next() {
+            let arg = match arg.into_string() {
+                Ok(arg) => arg,
+                Err(arg) => {
+                    args.cases.push(arg.into());
+                    continue;
+                }
+            };
+            let (flag, inline_value) = split_flag(&arg);
+            let mut value = || flag_value(flag, inline_value, &mut raw);
+            match flag {
+                "-h" | "--help" => return Ok(None),
+                "--bin-name" => args.bin_name = Some(utf8_value(flag, value()?)?),
+                "--bin-path" => args.bin_path = Some(value()?.into()),
+                "--register" => {
+                    let value = utf8_value(flag, value()?)?;
+                    let bin = match value.split_once('=') {
+                        Some((name, path)) => {
+                            (name.to_owned(), trycmd::schema::Bin::Path(path.into()))
+                        }
+                        None => {
+                            let bin = match find_in_path(&value) {
+                                Some(path) => trycmd::schema::Bin::Path(path),
+                                None => trycmd::schema::Bin::Error(trycmd::Error::new(format!(
+                                    "`{value}` not found in PATH"
+                                ))),
+                            };
+                            (value, bin)
+                        }
+                    };
+                    args.bins.push(bin);
+                }
+                "--env" => args.env.push(parse_env(&utf8_value(flag, value()?)?)?),
+                "--timeout" => args.timeout = Some(parse_timeout(&utf8_value(flag, value()?)?)?),
+                "--hermetic" => args.hermetic = true,
+                "--exclude" => args.exclude.push(value()?.into()),
+                "--shard" => args.shard = Some(parse_shard(&utf8_value(flag, value()?)?)?),
+                "--mode" => {
+                    let value = utf8_value(flag, value()?)?;
+                    let mode = match value.as_str() {
+                        "fail" | "overwrite" | "dump" => value,
+                        _ => {
+                            return Err(format!(
+                                "invalid `--mode {value}`, expected `fail`, `overwrite`, or `dump`"
+                            ));
+                        }
+                    };
+                    args.mode = Some(mode);
+                }
+                _ if flag.starts_with('-') => return Err(format!("unknown option `{flag}`")),
+                // Picked up by `TestCases::new`
+                _ if flag.starts_with("trycmd=") => {}
+                _ => args.cases.push(arg.into()),
+            }
+        }
+
+        if args.cases.is_empty() {
+            return Err("no test cases specified".to_owned());
+        }
+
+        Ok(Some(args))
+    }
+}
+
+struct RecordArgs {
+    case: std::path::PathBuf,
+    bin: String,
+    bin_path: Option<std::path::PathBuf>,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    input: Option<std::path::PathBuf>,
+    timeout: Option<std::time::Duration>,
+}
+
+impl RecordArgs {
+    fn parse(raw: impl IntoIterator<Item = std::ffi::OsString>) -> Result<Option<Self>, String> {
+        let mut case = None;
+        let mut bin_path = None;
+        let mut env = Vec::new();
+        let mut input = None;
+        let mut timeout = None;
+
+        let mut raw = raw.into_iter();
+        while let Some(arg) = raw.next() {
+            let arg = arg
+                .into_string()
+                .map_err(|arg| format!("invalid UTF-8: {}", arg.to_string_lossy()))?;
+            let (flag, inline_value) = split_flag(&arg);
+            let mut value = || flag_value(flag, inline_value, &mut raw);
+            match flag {
+                "-h" | "--help" => return Ok(None),
+                "--" => break,
+                "--bin-path" => bin_path = Some(value()?.into()),
+                "--env" => env.push(parse_env(&utf8_value(flag, value()?)?)?),
+                "--in" => input = Some(value()?.into()),
+                "--timeout" => timeout = Some(parse_timeout(&utf8_value(flag, value()?)?)?),
+                _ if flag.starts_with('-') => return Err(format!("unknown option `{flag}`")),
+                _ if case.is_none() => case = Some(arg.into()),
+                _ => return Err(format!("unexpected argument `{arg}`, expected `--`")),
+            }
+        }
+        let case = case.ok_or("no test case specified")?;
+
+        let mut args = raw
+            .map(|arg| {
+                arg.into_string()
+                    .map_err(|arg| format!("invalid UTF-8: {}", arg.to_string_lossy()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if args.is_empty() {
+            return Err("no bin specified".to_owned());
+        }
+        let bin = args.remove(0);
+
+        Ok(Some(Self {
+            case,
+            bin,
+            bin_path,
+            args,
+            env,
+            input,
+            timeout,
+        }))
+    }
+}
+
+struct ConvertArgs {
+    source: std::path::PathBuf,
+    target: std::path::PathBuf,
+}
+
+impl ConvertArgs {
+    fn parse(raw: impl IntoIterator<Item = std::ffi::OsString>) -> Result<Option<Self>, String> {
+        let mut paths = Vec::new();
+        for arg in raw {
+            if arg == "-h" || arg == "--help" {
+                return Ok(None);
+            } else if arg.to_string_lossy().starts_with('-') {
+                return Err(format!("unknown option `{}`", arg.to_string_lossy()));
+            }
+            paths.push(std::path::PathBuf::from(arg));
+        }
+        let [source, target]: [std::path::PathBuf; 2] = paths
+            .try_into()
+            .map_err(|_| "expected `<SOURCE> <TARGET>`".to_owned())?;
+        Ok(Some(Self { source, target }))
+    }
+}
+
+fn split_flag(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((flag, value)) if flag.starts_with("--") => (flag, Some(value)),
+        _ => (arg, None),
+    }
+}
+
+fn flag_value(
+    flag: &str,
+    inline_value: Option<&str>,
+    raw: &mut impl Iterator<Item = std::ffi::OsString>,
+) -> Result<std::ffi::OsString, String> {
+    inline_value
+        .map(std::ffi::OsString::from)
+        .or_else(|| raw.next())
+        .ok_or_else(|| format!("`{flag}` requires a value"))
+}
+
+/// Read a flag's value that must be text, unlike paths
+fn utf8_value(flag: &str, value: std::ffi::OsString) -> Result<String, String> {
+    value
+        .into_string()
+        .map_err(|value| format!("invalid UTF-8 in `{flag} {}`", value.to_string_lossy()))
+}
+
+fn parse_env(value: &str) -> Result<(String, String), String> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("`--env {value}` must be `KEY=VALUE`"))?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+fn parse_timeout(value: &str) -> Result<std::time::Duration, String> {
+    humantime::parse_duration(value).map_err(|e| format!("invalid `--timeout {value}`: {e}"))
+}
+
+fn parse_shard(value: &str) -> Result<(usize, usize), String> {
+    value
+        .split_once('/')
+        .and_then(|(index, total)| Some((index.parse().ok()?, total.parse().ok()?)))
+        .ok_or_else(|| format!("`--shard {value}` must be `INDEX/TOTAL`"))
+}
+
+fn find_in_path(name: &str) -> Option<std::path::PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .map(|dir| dir.join(format!("{}{}", name, std::env::consts::EXE_SUFFIX)))
+        .find(|path| path.is_file())
+}
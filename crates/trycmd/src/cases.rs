@@ -1,11 +1,15 @@
 use std::borrow::Cow;
 
+#[cfg(feature = "color")]
+use anstream::panic;
+
 /// Entry point for running tests
 #[derive(Debug, Default)]
 pub struct TestCases {
     runner: std::cell::RefCell<crate::RunnerSpec>,
     bins: std::cell::RefCell<crate::BinRegistry>,
     substitutions: std::cell::RefCell<snapbox::Redactions>,
+    mode: std::cell::RefCell<Option<crate::Mode>>,
     has_run: std::cell::Cell<bool>,
 }
 
@@ -19,12 +23,27 @@ impl TestCases {
     }
 
     /// Load tests from `glob`
+    ///
+    /// A line number, like `README.md:120`, only runs the code block containing that line.
+    ///
+    /// Directories, like `tests/cmd/*/`, are loaded from their `cmd.toml` or `case.trycmd`.
     pub fn case(&self, glob: impl AsRef<std::path::Path>) -> &Self {
         self.runner.borrow_mut().case(glob.as_ref(), None);
         self
     }
 
+    /// Don't load tests matching `glob`
+    ///
+    /// Unlike [`TestCases::skip`], these aren't reported.  Tests can also be excluded with a
+    /// `.trycmdignore` in their directory, using gitignore syntax.
+    pub fn exclude(&self, glob: impl AsRef<std::path::Path>) -> &Self {
+        self.runner.borrow_mut().exclude(glob.as_ref());
+        self
+    }
+
     /// Overwrite expected status for a test
+    ///
+    /// A line number, like `README.md:120`, only overwrites the code block containing that line.
     pub fn pass(&self, glob: impl AsRef<std::path::Path>) -> &Self {
         self.runner
             .borrow_mut()
@@ -33,6 +52,8 @@ impl TestCases {
     }
 
     /// Overwrite expected status for a test
+    ///
+    /// See [`TestCases::pass`] for line numbers.
     pub fn fail(&self, glob: impl AsRef<std::path::Path>) -> &Self {
         self.runner
             .borrow_mut()
@@ -41,6 +62,8 @@ impl TestCases {
     }
 
     /// Overwrite expected status for a test
+    ///
+    /// See [`TestCases::pass`] for line numbers.
     pub fn interrupted(&self, glob: impl AsRef<std::path::Path>) -> &Self {
         self.runner.borrow_mut().case(
             glob.as_ref(),
@@ -50,6 +73,8 @@ impl TestCases {
     }
 
     /// Overwrite expected status for a test
+    ///
+    /// See [`TestCases::pass`] for line numbers.
     pub fn skip(&self, glob: impl AsRef<std::path::Path>) -> &Self {
         self.runner
             .borrow_mut()
@@ -57,6 +82,18 @@ impl TestCases {
         self
     }
 
+    /// Only run shard `index` (starting from 1) of `total`, for splitting tests across machines
+    ///
+    /// Cases are assigned by a hash of their path, so each shard gets the same cases on every
+    /// machine.  Without this, it is read from `TRYCMD_SHARD=<index>/<total>`.  An `index` outside
+    /// of `1..=total` fails the run.
+    pub fn shard(&self, index: usize, total: usize) -> &Self {
+        self.runner
+            .borrow_mut()
+            .shard(crate::Shard::new(index, total));
+        self
+    }
+
     /// Set default bin, by path, for commands
     pub fn default_bin_path(&self, path: impl AsRef<std::path::Path>) -> &Self {
         let bin = Some(crate::schema::Bin::Path(path.as_ref().into()));
@@ -83,6 +120,78 @@ impl TestCases {
         self
     }
 
+    /// Run commands in a hermetic environment
+    ///
+    /// Instead of the host's environment, commands start from `PATH` and a few other variables for
+    /// running programs with:
+    /// - `LANG=C`, `LC_ALL=C`, `TZ=UTC`, `TERM=dumb`, and `SOURCE_DATE_EPOCH=0`
+    /// - In a sandbox, `HOME` and `XDG_*` within `home/`, redacted as `[HOME]`, which `*.in/home/`
+    ///   can seed
+    ///
+    /// This can also be set per case with `env.hermetic = true`.
+    pub fn hermetic(&self, yes: bool) -> &Self {
+        self.runner.borrow_mut().hermetic(yes);
+        self
+    }
+
+    /// Also run literate code blocks with this language
+    ///
+    /// By default, `trycmd` and `console` blocks are run.  This can also be set per file with
+    /// `languages = ["sh"]` in the front matter.
+    pub fn language(&self, lang: impl Into<String>) -> &Self {
+        let mut runner = self.runner.borrow_mut();
+        crate::schema::extend_unique(&mut runner.literate().languages, &[lang.into()]);
+        self
+    }
+
+    /// Also start commands in literate code blocks with this line prefix, like `"% "`
+    ///
+    /// By default, `"$ "` is used.  This can also be set per file with `prompts = ["% "]` in the
+    /// front matter.
+    pub fn prompt(&self, prefix: impl Into<String>) -> &Self {
+        let mut runner = self.runner.borrow_mut();
+        crate::schema::extend_unique(&mut runner.literate().prompts, &[prefix.into()]);
+        self
+    }
+
+    /// Also continue commands in literate code blocks with this line prefix, like `"... "`
+    ///
+    /// By default, `"> "` is used.  This can also be set per file with `continuations = ["... "]`
+    /// in the front matter.
+    pub fn continuation(&self, prefix: impl Into<String>) -> &Self {
+        let mut runner = self.runner.borrow_mut();
+        crate::schema::extend_unique(&mut runner.literate().continuations, &[prefix.into()]);
+        self
+    }
+
+    /// Treat lines with this prefix, like `"# "`, before a command in literate code blocks as
+    /// comments
+    ///
+    /// This can also be set per file with `comments = ["# "]` in the front matter.
+    pub fn comment(&self, prefix: impl Into<String>) -> &Self {
+        let mut runner = self.runner.borrow_mut();
+        crate::schema::extend_unique(&mut runner.literate().comments, &[prefix.into()]);
+        self
+    }
+
+    /// Interpret commands in literate code blocks with a subset of shell syntax
+    ///
+    /// This enables:
+    /// - `$VAR` and `${VAR}` from the command's environment
+    /// - `[ROOT]` and `[CWD]` for the sandbox
+    /// - `$(cmd args...)` with registered bins, or the built-in `cat` for reading files, without
+    ///   splitting the result into multiple arguments
+    /// - `cmd1 | cmd2` pipelines, checking the status of the last command to fail and the final
+    ///   output with `stderr` from every command
+    /// - `>`, `>>`, `2>`, and `2>>` for writing to files in the sandbox
+    ///
+    /// `'...'` disables expansion.  This can also be set per file with `shell-syntax = true` in the
+    /// front matter.
+    pub fn shell_syntax(&self, yes: bool) -> &Self {
+        self.runner.borrow_mut().literate().shell_syntax = yes;
+        self
+    }
+
     /// Add a bin to the "PATH" for cases to use
     pub fn register_bin(
         &self,
@@ -121,6 +230,7 @@ impl TestCases {
     /// - `[EXE]`
     /// - `[CWD]`
     /// - `[ROOT]`
+    /// - `[HOME]`
     ///
     /// ## Example
     ///
@@ -169,17 +279,64 @@ impl TestCases {
         Ok(self)
     }
 
+    /// Handle mismatches like `TRYCMD=<mode>`, instead of reading it from the environment
+    ///
+    /// `mode` is one of:
+    /// - `fail` (default): report mismatches as failures
+    /// - `overwrite`: update the expected output to match
+    /// - `dump`: write the actual output to `dump/`
+    ///
+    /// Anything else is treated as `fail`.
+    pub fn mode(&self, mode: impl AsRef<std::ffi::OsStr>) -> &Self {
+        *self.mode.borrow_mut() = Some(parse_mode(Some(mode.as_ref())));
+        self
+    }
+
     /// Run tests
     ///
     /// This will happen on `drop` if not done explicitly
     pub fn run(&self) {
+        if let Err(summary) = self.try_run() {
+            panic!("{}", summary);
+        }
+    }
+
+    /// Run tests, returning a summary of the failures rather than panicking
+    ///
+    /// Like [`TestCases::run`], failures are reported on `stderr` as they happen.  This is for
+    /// running tests outside of `#[test]`, like from a `main`.
+    pub fn try_run(&self) -> Result<(), String> {
         self.has_run.set(true);
 
-        let mode = parse_mode(std::env::var_os("TRYCMD").as_deref());
+        let mode = self
+            .mode
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| parse_mode(std::env::var_os("TRYCMD").as_deref()));
         mode.initialize().unwrap();
 
+        let keep_sandbox = parse_keep_sandbox(std::env::var_os("TRYCMD_KEEP_SANDBOX").as_deref());
+        self.runner.borrow_mut().keep_sandbox(keep_sandbox);
+
+        if std::env::var_os("TRYCMD_RERUN").as_deref() == Some(std::ffi::OsStr::new("failed")) {
+            let last_failed = self.runner.borrow().last_failed();
+            if let Some(last_failed) = last_failed {
+                self.runner.borrow_mut().rerun(last_failed.read());
+            }
+        }
+
+        if let Some(shard) = std::env::var_os("TRYCMD_SHARD") {
+            let mut runner = self.runner.borrow_mut();
+            if !runner.has_shard() {
+                runner.shard(
+                    crate::Shard::parse(&shard.to_string_lossy())
+                        .map_err(|e| format!("Invalid `TRYCMD_SHARD`: {}", e).into()),
+                );
+            }
+        }
+
         let runner = self.runner.borrow_mut().prepare();
-        runner.run(&mode, &self.bins.borrow(), &self.substitutions.borrow());
+        runner.run(&mode, &self.bins.borrow(), &self.substitutions.borrow())
     }
 }
 
@@ -238,3 +395,13 @@ fn parse_mode(var: Option<&std::ffi::OsStr>) -> crate::Mode {
         crate::Mode::Fail
     }
 }
+
+fn parse_keep_sandbox(var: Option<&std::ffi::OsStr>) -> crate::KeepSandbox {
+    if var == Some(std::ffi::OsStr::new("failed")) {
+        crate::KeepSandbox::Failed
+    } else if var == Some(std::ffi::OsStr::new("all")) {
+        crate::KeepSandbox::All
+    } else {
+        crate::KeepSandbox::Never
+    }
+}
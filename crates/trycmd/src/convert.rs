@@ -0,0 +1,263 @@
+/// Convert a case between `*.toml` and `*.trycmd` / `*.md`
+///
+/// `target`, and its `*.stdout` for `*.toml`, must not exist yet.  The `*.in/` and `*.out/` directories are shared when `source` and
+/// `target` have the same file stem.
+///
+/// Returns warnings for behavior that changed in the conversion.  Content that can't be
+/// represented in `target` is an error, like binary data, `stdin`, or separately verified
+/// `stdout` and `stderr` in `*.trycmd`.  Settings for the whole file, like `timeout`, `env`, and
+/// `fs`, go in `*.trycmd` front matter.
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// let warnings = trycmd::convert("tests/cmd/help.toml", "tests/cmd/help.trycmd").unwrap();
+/// for warning in warnings {
+///     eprintln!("warning: {}", warning);
+/// }
+/// ```
+pub fn convert(
+    source: impl AsRef<std::path::Path>,
+    target: impl AsRef<std::path::Path>,
+) -> Result<Vec<String>, crate::Error> {
+    let source = source.as_ref();
+    let target = target.as_ref();
+    let source_format = Format::from_path(source)?;
+    let target_format = Format::from_path(target)?;
+    let mut outputs = vec![target.to_owned()];
+    if let Format::Toml = target_format {
+        outputs.push(target.with_extension("stdout"));
+    }
+    if let Some(output) = outputs.iter().find(|p| p.exists()) {
+        return Err(format!("{} already exists", output.display()).into());
+    }
+
+    let mut warnings = Vec::new();
+    if !crate::schema::TryCmd::load_matrix(source)?.is_empty() {
+        return Err("`matrix` can't be converted".into());
+    }
+    match (source_format, target_format) {
+        (Format::Toml, Format::Trycmd) => {
+            toml_to_trycmd(source, target, &mut warnings)?;
+        }
+        (Format::Trycmd, Format::Toml) => {
+            trycmd_to_toml(source, target)?;
+        }
+        (Format::Toml, Format::Toml) | (Format::Trycmd, Format::Trycmd) => {
+            return Err(format!(
+                "{} and {} are the same format",
+                source.display(),
+                target.display()
+            )
+            .into());
+        }
+    }
+
+    for ext in ["in", "out"] {
+        let dir = source.with_extension(ext);
+        let target_dir = target.with_extension(ext);
+        if dir != target_dir && dir.exists() {
+            warnings.push(format!(
+                "{} must be moved to {}",
+                dir.display(),
+                target_dir.display()
+            ));
+        }
+    }
+
+    Ok(warnings)
+}
+
+enum Format {
+    Toml,
+    Trycmd,
+}
+
+impl Format {
+    fn from_path(path: &std::path::Path) -> Result<Self, crate::Error> {
+        let ext = path
+            .extension()
+            .ok_or_else(|| crate::Error::new("No extension"))?;
+        if ext == std::ffi::OsStr::new("toml") {
+            Ok(Self::Toml)
+        } else if ext == std::ffi::OsStr::new("trycmd") || ext == std::ffi::OsStr::new("md") {
+            Ok(Self::Trycmd)
+        } else {
+            Err(format!("Unsupported extension: {}", ext.to_string_lossy()).into())
+        }
+    }
+}
+
+fn toml_to_trycmd(
+    source: &std::path::Path,
+    target: &std::path::Path,
+    warnings: &mut Vec<String>,
+) -> Result<(), crate::Error> {
+    let sequence =
+        crate::schema::TryCmd::load(source, Default::default(), &Default::default(), None)?;
+    let mut step = sequence
+        .steps
+        .into_iter()
+        .next()
+        .expect("`*.toml` is always one step");
+
+    if step.binary {
+        return Err("`binary = true` can't be represented in `*.trycmd`".into());
+    }
+    if step.stdin.is_some() {
+        return Err("`stdin` can't be represented in `*.trycmd`".into());
+    }
+    if !sequence.mocks.is_empty() || step.expected_mocks.is_some() {
+        return Err("`mocks` can't be converted".into());
+    }
+    let front_matter = crate::schema::FrontMatter {
+        // `KEY=VALUE` on the command covers `env.add`
+        env: crate::schema::Env {
+            inherit: step.env.inherit,
+            hermetic: step.env.hermetic,
+            remove: std::mem::take(&mut step.env.remove),
+            ..Default::default()
+        },
+        fs: portable_fs(&sequence.fs, source, target)?,
+        timeout: step.timeout,
+        ..Default::default()
+    };
+
+    if !step.stderr_to_stdout {
+        let is_empty = |d: &crate::Data| d.render().map(|d| d.is_empty()).unwrap_or(false);
+        step.expected_stdout = match (step.expected_stdout.take(), step.expected_stderr.take()) {
+            (Some(stdout), Some(stderr)) => {
+                if is_empty(&stderr) {
+                    Some(stdout)
+                } else if is_empty(&stdout) {
+                    Some(stderr)
+                } else {
+                    return Err(
+                        "separate `stdout` and `stderr` can't be represented in `*.trycmd`".into(),
+                    );
+                }
+            }
+            (Some(stdout), None) => {
+                warnings.push("`stderr` was unverified but will be merged into `stdout`".into());
+                Some(stdout)
+            }
+            (None, Some(stderr)) => {
+                warnings.push("`stdout` was unverified but will be merged into `stderr`".into());
+                Some(stderr)
+            }
+            (None, None) => None,
+        };
+    }
+
+    let mut raw = front_matter.to_trycmd()?;
+    raw.push_str(&step.to_trycmd()?);
+    std::fs::write(target, raw)
+        .map_err(|e| format!("Failed to write {}: {}", target.display(), e))?;
+
+    Ok(())
+}
+
+fn trycmd_to_toml(source: &std::path::Path, target: &std::path::Path) -> Result<(), crate::Error> {
+    let sequence =
+        crate::schema::TryCmd::load(source, Default::default(), &Default::default(), None)?;
+    if sequence.steps.len() != 1 {
+        return Err(format!(
+            "`*.toml` can only represent one command, found {}",
+            sequence.steps.len()
+        )
+        .into());
+    }
+    let step = sequence
+        .steps
+        .into_iter()
+        .next()
+        .expect("checked for one step");
+    if !sequence.mocks.is_empty() || step.expected_mocks.is_some() {
+        return Err("`mocks` can't be converted".into());
+    }
+    if step.shell_cmdline.is_some() {
+        return Err("`shell-syntax` commands can't be represented in `*.toml`".into());
+    }
+    if !step.files.is_empty() || !step.expected_files.is_empty() {
+        return Err("`file=` and `expect-file=` blocks can't be represented in `*.toml`".into());
+    }
+
+    let one_shot = crate::schema::OneShot {
+        bin: step.bin,
+        args: crate::schema::Args::Joined(crate::schema::JoinedArgs::from_vec(step.args)),
+        env: step.env,
+        stderr_to_stdout: true,
+        status: step
+            .expected_status
+            .filter(|s| *s != crate::schema::CommandStatus::Success),
+        timeout: step.timeout,
+        capture: step.captures,
+        fs: portable_fs(&sequence.fs, source, target)?,
+        ..Default::default()
+    };
+    let raw = one_shot.to_toml()?;
+    std::fs::write(target, raw)
+        .map_err(|e| format!("Failed to write {}: {}", target.display(), e))?;
+    if let Some(stdout) = step.expected_stdout {
+        stdout.write_to_path(&target.with_extension("stdout"))?;
+    }
+
+    Ok(())
+}
+
+/// `fs` as written in `target`, leaving out what its `*.in/` and `*.out/` imply
+///
+/// Paths are relative to `target`, with `source`'s `*.in/` moved to `target`'s.
+fn portable_fs(
+    fs: &crate::schema::Filesystem,
+    source: &std::path::Path,
+    target: &std::path::Path,
+) -> Result<crate::schema::Filesystem, crate::Error> {
+    let source_in = source.with_extension("in");
+    let source_in = source_in
+        .exists()
+        .then(|| snapbox::dir::resolve_dir(source_in).map_err(|e| e.to_string()))
+        .transpose()?;
+    let target_dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let target_dir = snapbox::dir::resolve_dir(target_dir)
+        .map_err(|e| format!("Failed to read {}: {}", target_dir.display(), e))?;
+    let target_in = target_dir.join(target.with_extension("in").file_name().unwrap_or_default());
+    let relative = |path: &std::path::Path| {
+        let path = match source_in.as_deref().map(|s| path.strip_prefix(s)) {
+            Some(Ok(rest)) => target_in.join(rest),
+            _ => path.to_owned(),
+        };
+        let common = path
+            .components()
+            .zip(target_dir.components())
+            .take_while(|(p, t)| p == t)
+            .count();
+        let mut relative = std::path::PathBuf::new();
+        for _ in target_dir.components().skip(common) {
+            relative.push("..");
+        }
+        relative.extend(path.components().skip(common));
+        if relative.as_os_str().is_empty() {
+            relative.push(".");
+        }
+        relative
+    };
+
+    let cwd = fs.cwd.as_deref().map(relative);
+    let (base, cwd) = if fs.base == source_in {
+        (None, cwd.filter(|_| fs.cwd != fs.base))
+    } else if source_in.is_none() && fs.base == fs.cwd {
+        // Defaults to the cwd
+        (None, cwd)
+    } else {
+        (
+            fs.base.as_deref().map(relative),
+            cwd.filter(|_| fs.cwd != fs.base),
+        )
+    };
+    let sandbox = (fs.sandbox() != source.with_extension("out").exists()).then_some(fs.sandbox());
+    Ok(crate::schema::Filesystem { cwd, base, sandbox })
+}
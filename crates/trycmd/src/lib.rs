@@ -57,6 +57,20 @@
 //!
 //! You can then copy over to `tests/cmd` the cases you want to test
 //!
+//! To create a new case from running a command, run
+//! ```console
+//! $ trycmd record --env KEY=VALUE --in tests/cmd/fixture tests/cmd/help.toml -- my-cmd --help
+//! ```
+//! This will write `help.toml`, `help.stdout`, `help.stderr`, `help.in/`, and `help.out/` or, for
+//! `*.md` / `*.trycmd` files, append a `console` code block.  See also [`Recording`].
+//!
+//! To switch a case between `*.toml` and `*.trycmd`, run
+//! ```console
+//! $ trycmd convert tests/cmd/help.toml tests/cmd/help.trycmd
+//! ```
+//! This will warn about any behavior that changed, like `stderr` being merged into `stdout`.  See
+//! also [`convert`].
+//!
 //! To update snapshots, run
 //! ```console
 //! $ TRYCMD=overwrite cargo test --test cli_tests
@@ -67,12 +81,46 @@
 //! ```console
 //! cargo test --test cli_tests -- cli_tests trycmd=name1 trycmd=name2...
 //! ```
+//! Add a line number, like `trycmd=README.md:120`, to only run the code block containing that
+//! line.  `*.out/` isn't verified when only some code blocks are run.
+//!
+//! To leave cases out entirely, list them in a `.trycmdignore` in their directory, using gitignore
+//! syntax, or use [`TestCases::exclude`].
+//!
+//! To re-run only the cases that failed last time, run
+//! ```console
+//! $ TRYCMD_RERUN=failed cargo test --test cli_tests
+//! ```
+//! Failures are recorded in `target/` after each run, keeping those of cases that didn't run.
+//! When none are recorded, all cases are run.
+//!
+//! To split cases across CI machines, run on each of them
+//! ```console
+//! $ TRYCMD_SHARD=1/3 cargo test --test cli_tests
+//! ```
+//! Each case is assigned to one shard by a hash of its path, so every machine agrees on which
+//! cases it runs.  The output starts with the shard and how many of the cases it has.
+//!
+//! To inspect what a failing case left in its sandbox, run
+//! ```console
+//! $ TRYCMD_KEEP_SANDBOX=failed cargo test --test cli_tests
+//! ```
+//! This reports where each sandbox was kept, with a `trycmd-rerun.sh` in it to re-run the failed
+//! step.  Use `TRYCMD_KEEP_SANDBOX=all` to keep the sandboxes of passing cases as well.
 //!
 //! To debug what `trycmd` is doing, run `cargo test -F trycmd/debug`.
 //!
+//! To run cases outside of a `#[test]`, like for shell scripts or non-Rust programs, use the
+//! `trycmd` binary:
+//! ```console
+//! $ cargo install trycmd
+//! $ trycmd --register my-cmd 'tests/cmd/*.toml' README.md trycmd=name1
+//! ```
+//! See `trycmd --help` for the options mirroring [`TestCases`].
+//!
 //! ## File Formats
 //!
-//! For `tests/cmd/help.trycmd`, `trycmd` will look for:
+//! For `tests/cmd/help.trycmd` (or `*.md`, `*.rs`, `*.rst`, `*.adoc`), `trycmd` will look for:
 //! - `tests/cmd/help.in/`
 //! - `tests/cmd/help.out/`
 //!
@@ -80,9 +128,15 @@
 //! - `tests/cmd/help.stdin`
 //! - `tests/cmd/help.stdout`
 //! - `tests/cmd/help.stderr`
+//! - `tests/cmd/help.mocks`
 //! - `tests/cmd/help.in/`
 //! - `tests/cmd/help.out/`
 //!
+//! Alternatively, a case can be a directory, like `tests/cmd/help/`, holding `cmd.toml` or
+//! `case.trycmd` along with `stdin`, `stdout`, `stderr`, `mocks`, `in/`, and `out/`.  Load
+//! these with `.case("tests/cmd/*/")` or `.case("tests/cmd/help/")`, as only cases found as a
+//! directory use this layout; `TRYCMD=overwrite` and `TRYCMD=dump` keep it.
+//!
 //! ### `*.trycmd`
 //!
 //! `*.trycmd` / `*.md` files are literate test cases good for:
@@ -94,14 +148,52 @@
 //! The syntax is:
 //! - Test cases live inside of ` ``` ` fenced code blocks
 //!   - Everything out of them is ignored
+//!   - Except for blocks with a `file=<path>` attribute, like ` ```toml,file=config.toml `,
+//!     which are written to `<path>` in the sandbox before the next command, and blocks with an
+//!     `expect-file=<path>` attribute, which are compared to `<path>` after the prior command
+//!   - Except for blocks with the `expect-mocks` attribute, which are compared to the calls the
+//!     prior command made to `mocks`, like `*.mocks`
+//!   - Except for `<!-- trycmd:include <path> -->` lines, which run the steps from `<path>`,
+//!     relative to the file, in their place.  `TRYCMD=overwrite` updates them in `<path>`.
 //!   - Blocks with info strings with an unsupported language (not `trycmd`, `console`) or the
 //!     `ignore` attribute are ignored
+//!   - Once a command fails in a sandbox, later commands are skipped, except for blocks with the
+//!     `always-run` attribute, like for cleanup, and failures in blocks with the
+//!     `continue-on-error` attribute don't cause any skipping
 //! - "`$ `" line prefix starts a new command
 //! - "`> `" line prefix appends to the prior command
 //! - "`? <status>`" line indicates the exit code (like `echo "? $?"`) and `<status>` can be
 //!   - An exit code
 //!   - `success` *(default)*, `failed`, `interrupted`, `skipped`
+//! - "`=> <NAME> [<regex>]`" lines save the output for later commands to use as `[NAME]` in their
+//!   arguments and environment, redacting it as `[NAME]`
+//!   - With a regex, only the group named `NAME` or, if there is none, the whole match is saved
+//!   - `NAME` is `A-Z` and `_`, other than the built-in `CWD`, `ROOT`, `EXE`, and `HOME`
 //!  - All following lines are treated as stdout + stderr
+//! - "`$ cd <dir>`" changes the directory of later commands, and `[CWD]`, within the sandbox
+//!
+//! The languages, prompts, and continuations can be extended, and comments enabled, with
+//! [`TestCases::language`], [`TestCases::prompt`], [`TestCases::continuation`], and
+//! [`TestCases::comment`] or per-file with [TOML](https://toml.io) front matter:
+//! ~~~md
+//! +++
+//! languages = ["sh", "shell-session"]
+//! prompts = ["% "]
+//! continuations = ["... "]
+//! comments = ["# "]
+//! +++
+//! ~~~
+//! The front matter can also set `bin`, `env`, `fs`, and `timeout` for every command, like with
+//! `*.toml`, with `KEY=VALUE` on a command taking precedence over `env`.  A `[matrix]` table runs
+//! the file once per combination.
+//!
+//! Comment lines are only recognized when they lead up to the next command or the end of the
+//! block, so separate output that looks like a comment from them with a blank line.
+//!
+//! With [`TestCases::shell_syntax`] or `shell-syntax = true` in the front matter, commands are
+//! interpreted with a subset of shell syntax, including `$VAR`, `[CWD]`, and `$(cat file.txt)`, so
+//! a step can use the results of a prior step.  Pipelines, like `mytool export | mytool import`,
+//! run as one step and redirections, like `> out.json`, write into the sandbox.
 //!
 //! The command is then split with [shlex](https://crates.io/crates/shlex), allowing quoted content
 //! to allow spaces.  The first argument is the program to run which maps to `bin.name` in the
@@ -128,6 +220,19 @@
 //! For a more complete example, see:
 //! <https://github.com/assert-rs/trycmd/tree/main/examples/demo_trycmd>.
 //!
+//! ### `*.rs`
+//!
+//! Code blocks in `///` and `//!` doc comments are treated like `*.trycmd`, keeping examples in your
+//! API docs or `clap` help from going stale.  `TRYCMD=overwrite` will preserve the comment prefix.
+//! Code blocks without a language are doctests and are skipped.
+//!
+//! ### `*.rst` and `*.adoc`
+//!
+//! [reStructuredText](https://docutils.sourceforge.io/rst.html) `.. code-block:: console`
+//! directives and [AsciiDoc](https://asciidoc.org/) `[source,console]` listings are treated like
+//! `*.trycmd` code blocks.  As reStructuredText blocks end with indentation, their first trailing
+//! blank line is included in the output.
+//!
 //! ### `*.toml`
 //!
 //! As an alternative to `.trycmd`, the `toml` are good for:
@@ -139,6 +244,13 @@
 //! Basic parameters:
 //! - `bin.name`: The name of the binary target from `Cargo.toml` to be used to find the file path
 //! - `args`: the arguments (including flags and option) passed to the binary
+//! - `capture`: save the output as `[NAME]`, like with `=> <NAME> [<regex>]` in `*.trycmd`
+//! - `matrix`: run the case once per combination of values, like `matrix.format = ["json", "yaml"]`,
+//!   substituting `{{format}}` in `args`, `env`, and `stdin` and preferring expected files like
+//!   `*.format-json.stdout`
+//! - `env.hermetic`: start from a deterministic environment, see [`TestCases::hermetic`]
+//! - `mocks`: fake commands, on Unix, that the binary can call instead of real programs like `git`
+//!   (see below)
 //!
 //! #### `*.stdin`
 //!
@@ -165,11 +277,36 @@
 //! - `[EXE]` as part of the line: On Windows, matches `.exe`, ignored otherwise
 //! - `[ROOT]` as part of the line: The root directory for where the test is running
 //! - `[CWD]` as part of the line: The current working directory within the root
+//! - `[HOME]` as part of the line: `HOME` within the root, with [`TestCases::hermetic`]
 //! - `[YOUR_NAME_HERE]` as part of the line: See [`TestCases::insert_var`]
 //!
 //! We will preserve these with `TRYCMD=dump` and will make a best-effort at preserving them with
 //! `TRYCMD=overwrite`.
 //!
+//! #### `*.mocks`
+//!
+//! Expected calls to `mocks`, one line per call of the command name and its arguments, quoted for
+//! `/bin/sh` when needed.
+//! - If not present, we'll not verify the calls
+//!
+//! Each mock is a table of `stdout`, `stderr`, and `code`, with `[[mocks.<name>.cases]]` for
+//! different responses when the arguments, quoted and joined like in `*.mocks`, match the shell
+//! pattern `args`:
+//! ```toml
+//! bin.name = "my-cmd"
+//! fs.sandbox = true
+//!
+//! [mocks.git]
+//! code = 1
+//!
+//! [[mocks.git.cases]]
+//! args = "clone *"
+//! stdout = "Cloning into 'repo'..."
+//! ```
+//! They are written as `/bin/sh` scripts into a temporary directory outside of the sandbox, which
+//! is put first on `PATH`.  Mocks still require a sandbox.  Literate files can define them in the
+//! front matter.
+//!
 //! ### `*.in/`
 //!
 //! When present, this will automatically be picked as the CWD for the command.
@@ -177,7 +314,8 @@
 //! `.keep` files will be ignored but their parent directories will be created.
 //!
 //! Tests are assumed to not modify files in `*.in/` unless an `*.out/` is provided or
-//! `fs.sandbox = true` is set in the `.toml` file.
+//! `fs.sandbox = true` is set in the `.toml` file.  Otherwise, the case fails with a list of
+//! the files that were created, modified, or deleted.
 //!
 //! ### `*.out/`
 //!
@@ -233,15 +371,21 @@ pub mod cargo;
 pub mod schema;
 
 mod cases;
+mod convert;
+mod mock;
+mod record;
 mod registry;
 mod runner;
+mod shell;
 mod spec;
 
 pub use cases::TestCases;
+pub use convert::convert;
+pub use record::Recording;
 pub use snapbox::assert::Error;
 
 pub(crate) use registry::BinRegistry;
-pub(crate) use runner::{Case, Mode, Runner};
-pub(crate) use spec::RunnerSpec;
+pub(crate) use runner::{Case, KeepSandbox, LastFailed, Mode, Runner};
+pub(crate) use spec::{RunnerSpec, Shard};
 
 pub(crate) use snapbox::Data;
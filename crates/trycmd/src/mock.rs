@@ -0,0 +1,236 @@
+//! Fake commands, as `/bin/sh` scripts, for programs a case calls out to
+//!
+//! Each call is appended to a log as a line of the command name and its arguments, quoted for
+//! `/bin/sh` when needed, joined with spaces.  `cases` match the quoted arguments.
+
+use std::collections::BTreeMap;
+
+use crate::shell::quote;
+
+/// Mocks written outside of the sandbox, so they don't show up in its files
+#[derive(Debug)]
+pub(crate) struct Mocks {
+    /// Removed when dropped
+    _dir: snapbox::dir::DirRoot,
+    /// Directory to put first on `PATH`
+    bin: std::path::PathBuf,
+    log: std::path::PathBuf,
+}
+
+impl Mocks {
+    /// Write `mocks` into `bin/` of a temporary directory, which also holds the log
+    pub(crate) fn install(
+        mocks: &BTreeMap<String, crate::schema::Mock>,
+    ) -> Result<Self, crate::Error> {
+        let dir = temp_dir()?;
+        let root = dir.path().expect("temporary directories have a path");
+        let bin = root.join("bin");
+        std::fs::create_dir_all(&bin)
+            .map_err(|e| format!("Failed to create {}: {}", bin.display(), e))?;
+        let log = root.join("calls.log");
+        for (name, mock) in mocks {
+            if name.is_empty() || name.contains(['/', '\\']) {
+                return Err(format!("Invalid mock name `{}`", name).into());
+            }
+            let path = bin.join(name);
+            std::fs::write(&path, script(name, mock, &log))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+            make_executable(&path)?;
+        }
+        Ok(Self {
+            _dir: dir,
+            bin,
+            log,
+        })
+    }
+
+    /// Put the mocks first on `PATH`
+    pub(crate) fn prepend_path(&self, env: &mut crate::schema::Env) -> Result<(), crate::Error> {
+        let path = env.get("PATH").unwrap_or_default();
+        let path = std::env::join_paths(
+            std::iter::once(self.bin.clone()).chain(std::env::split_paths(&path)),
+        )
+        .map_err(|e| format!("Failed to add mocks to `PATH`: {}", e))?;
+        let path = path
+            .into_string()
+            .map_err(|_| crate::Error::new("`PATH` is not UTF-8"))?;
+        env.add.insert("PATH".to_owned(), path);
+        Ok(())
+    }
+
+    /// Clear the calls, so they are recorded per step
+    pub(crate) fn reset(&self) -> Result<(), crate::Error> {
+        match std::fs::remove_file(&self.log) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(format!("Failed to remove {}: {}", self.log.display(), e).into()),
+        }
+    }
+
+    /// Calls since the last [`Mocks::reset`]
+    pub(crate) fn calls(&self) -> Result<Vec<u8>, crate::Error> {
+        match std::fs::read(&self.log) {
+            Ok(calls) => Ok(calls),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(format!("Failed to read {}: {}", self.log.display(), e).into()),
+        }
+    }
+}
+
+#[cfg(feature = "filesystem")]
+fn temp_dir() -> Result<snapbox::dir::DirRoot, crate::Error> {
+    snapbox::dir::DirRoot::mutable_temp().map_err(|e| e.to_string().into())
+}
+
+#[cfg(not(feature = "filesystem"))]
+fn temp_dir() -> Result<snapbox::dir::DirRoot, crate::Error> {
+    Err("`mocks` require the `filesystem` feature".into())
+}
+
+fn script(name: &str, mock: &crate::schema::Mock, log: &std::path::Path) -> String {
+    let mut script = String::from("#!/bin/sh\n");
+    script.push_str(LOG_ARGS);
+    script.push_str(&format!(
+        "printf '%s\\n' {}\"${{args:+ }}$args\" >> {}\n",
+        quote(name),
+        quote(&log.to_string_lossy())
+    ));
+    script.push_str("case \"$args\" in\n");
+    for case in &mock.cases {
+        script.push_str(&format!("  {})\n", pattern(&case.args)));
+        respond(&mut script, &case.response);
+    }
+    script.push_str("  *)\n");
+    respond(&mut script, &mock.response);
+    script.push_str("esac\n");
+    script
+}
+
+/// Join `"$@"` into `$args`, single-quoting each unless it only has safe characters
+const LOG_ARGS: &str = r#"args=
+for arg in "$@"; do
+  case "$arg" in
+    '' | *[!A-Za-z0-9_./=:@%+,-]*)
+      quoted= rest=$arg
+      while :; do
+        case "$rest" in
+          *\'*) head=${rest%%\'*}; rest=${rest#*\'}; quoted="$quoted$head'\\''" ;;
+          *) quoted="$quoted$rest"; break ;;
+        esac
+      done
+      args="$args${args:+ }'$quoted'" ;;
+    *) args="$args${args:+ }$arg" ;;
+  esac
+done
+"#;
+
+fn respond(script: &mut String, response: &crate::schema::MockResponse) {
+    if let Some(stdout) = &response.stdout {
+        script.push_str(&format!("    printf '%s' {}\n", quote(stdout)));
+    }
+    if let Some(stderr) = &response.stderr {
+        script.push_str(&format!("    printf '%s' {} >&2\n", quote(stderr)));
+    }
+    script.push_str(&format!("    exit {}\n", response.code.unwrap_or(0)));
+    script.push_str("    ;;\n");
+}
+
+/// Escape all but the glob syntax of `args` for a `case` pattern
+fn pattern(args: &str) -> String {
+    let mut pattern = String::new();
+    for c in args.chars() {
+        if !(c.is_ascii_alphanumeric() || "*?[]!-_.,/=:@+%".contains(c)) {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern
+}
+
+#[cfg(unix)]
+fn make_executable(path: &std::path::Path) -> Result<(), crate::Error> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+        .map_err(|e| format!("Failed to make {} executable: {}", path.display(), e).into())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &std::path::Path) -> Result<(), crate::Error> {
+    Err("`mocks` are only supported on Unix".into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn script_cases() {
+        let mock = crate::schema::Mock {
+            response: crate::schema::MockResponse {
+                stderr: Some("unknown".into()),
+                code: Some(1),
+                ..Default::default()
+            },
+            cases: vec![crate::schema::MockCase {
+                args: "clone 'a b' *".into(),
+                response: crate::schema::MockResponse {
+                    stdout: Some("it's done\n".into()),
+                    ..Default::default()
+                },
+            }],
+        };
+        let actual = script("git", &mock, std::path::Path::new("/tmp/mocks.log"));
+        let expected = r#"#!/bin/sh
+args=
+for arg in "$@"; do
+  case "$arg" in
+    '' | *[!A-Za-z0-9_./=:@%+,-]*)
+      quoted= rest=$arg
+      while :; do
+        case "$rest" in
+          *\'*) head=${rest%%\'*}; rest=${rest#*\'}; quoted="$quoted$head'\\''" ;;
+          *) quoted="$quoted$rest"; break ;;
+        esac
+      done
+      args="$args${args:+ }'$quoted'" ;;
+    *) args="$args${args:+ }$arg" ;;
+  esac
+done
+printf '%s\n' git"${args:+ }$args" >> /tmp/mocks.log
+case "$args" in
+  clone\ \'a\ b\'\ *)
+    printf '%s' "it's done
+"
+    exit 0
+    ;;
+  *)
+    printf '%s' unknown >&2
+    exit 1
+    ;;
+esac
+"#;
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn script_matches_quoted_args() {
+        let mock = crate::schema::Mock {
+            response: Default::default(),
+            cases: vec![crate::schema::MockCase {
+                args: "clone 'a b' *".into(),
+                response: crate::schema::MockResponse {
+                    stdout: Some("matched".into()),
+                    ..Default::default()
+                },
+            }],
+        };
+        let script = script("git", &mock, std::path::Path::new("/dev/null"));
+        let output = std::process::Command::new("sh")
+            .args(["-c", &script, "git", "clone", "a b", "x"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "matched");
+    }
+}
@@ -0,0 +1,387 @@
+use std::collections::BTreeMap;
+
+/// Generate a test case by running a command
+///
+/// ## Example
+///
+/// ```rust,no_run
+/// trycmd::Recording::new("my-cmd")
+///     .args(["--help"])
+///     .write("tests/cmd/help.toml")
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Recording {
+    bin: String,
+    bin_path: Option<std::path::PathBuf>,
+    args: Vec<String>,
+    env: BTreeMap<String, String>,
+    input: Option<std::path::PathBuf>,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Recording {
+    /// Record running `bin.name = <bin>`
+    pub fn new(bin: impl Into<String>) -> Self {
+        Self {
+            bin: bin.into(),
+            bin_path: None,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            input: None,
+            timeout: None,
+        }
+    }
+
+    /// Path to run for the bin, defaulting to the cargo target of the same name
+    pub fn bin_path(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.bin_path = Some(path.into());
+        self
+    }
+
+    /// Arguments to pass to the bin
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Environment variable to set for the bin
+    pub fn env(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.insert(key.into(), value.into());
+        self
+    }
+
+    /// Directory to run the bin in
+    ///
+    /// This will be copied to the case's `*.in/`, if it is somewhere else, once the command has
+    /// been recorded.  Any files the command creates or modifies will be written to the case's
+    /// `*.out/`, which is always created so the case runs in a sandbox.  Deleting files can't be
+    /// recorded.
+    pub fn input_dir(mut self, path: impl Into<std::path::PathBuf>) -> Self {
+        self.input = Some(path.into());
+        self
+    }
+
+    /// Timeout for the command
+    pub fn timeout(mut self, time: std::time::Duration) -> Self {
+        self.timeout = Some(time);
+        self
+    }
+
+    /// Run the command, writing the results to `case`
+    ///
+    /// - `*.toml`: Writes a new `*.toml` with `*.stdout`, `*.stderr`, and `*.out/`, none of which
+    ///   may exist yet
+    /// - `*.md` / `*.trycmd`: Appends a `console` code block, creating the file if needed
+    pub fn write(&self, case: impl AsRef<std::path::Path>) -> Result<(), crate::Error> {
+        let case = case.as_ref();
+        let ext = case
+            .extension()
+            .ok_or_else(|| crate::Error::new("No extension"))?;
+        if ext == std::ffi::OsStr::new("toml") {
+            let outputs = [
+                case.to_owned(),
+                case.with_extension("stdout"),
+                case.with_extension("stderr"),
+                case.with_extension("out"),
+            ];
+            if let Some(existing) = outputs.iter().find(|p| p.exists()) {
+                return Err(format!("{} already exists", existing.display()).into());
+            }
+            let recorded = self.run(case, false)?;
+            self.write_toml(case, recorded)
+        } else if ext == std::ffi::OsStr::new("trycmd") || ext == std::ffi::OsStr::new("md") {
+            let recorded = self.run(case, true)?;
+            self.append_markdown(case, recorded)
+        } else {
+            Err(format!("Unsupported extension: {}", ext.to_string_lossy()).into())
+        }
+    }
+
+    fn run(
+        &self,
+        case: &std::path::Path,
+        stderr_to_stdout: bool,
+    ) -> Result<Recorded, crate::Error> {
+        let bin = match &self.bin_path {
+            Some(path) => crate::schema::Bin::Path(path.clone()),
+            None => crate::BinRegistry::new().resolve_name(&self.bin),
+        };
+        let step = crate::schema::Step {
+            bin: Some(bin),
+            args: self.args.clone(),
+            env: crate::schema::Env {
+                add: self.env.clone(),
+                ..Default::default()
+            },
+            stderr_to_stdout,
+            timeout: self.timeout,
+            ..Default::default()
+        };
+
+        let case_input = case.with_extension("in");
+        let input = self
+            .input
+            .as_deref()
+            .map(|input| resolve_input(input, &case_input))
+            .transpose()?;
+        let fs_context = match &input {
+            Some(input) => sandbox(input)?,
+            None => snapbox::dir::DirRoot::none(),
+        };
+        let cwd = fs_context
+            .path()
+            .map(|p| p.to_owned())
+            .or_else(|| std::env::current_dir().ok());
+
+        let output = step
+            .to_command(cwd.as_deref())?
+            .output()
+            .map_err(|e| e.to_string())?;
+
+        let mut substitutions = snapbox::Redactions::new();
+        if let Some(root) = fs_context.path() {
+            substitutions.insert("[ROOT]", root.to_owned())?;
+        }
+        if let Some(cwd) = cwd {
+            substitutions.insert("[CWD]", cwd)?;
+        }
+        substitutions.insert("[EXE]", std::env::consts::EXE_SUFFIX)?;
+
+        let mut changed = None;
+        if let (Some(input), Some(root)) = (&input, fs_context.path()) {
+            changed = Some(changed_files(input, root)?);
+        }
+        fs_context
+            .close()
+            .map_err(|e| format!("Failed to cleanup sandbox: {}", e))?;
+
+        // Only now, so a failed recording doesn't leave part of a case behind
+        if let Some(input) = &input {
+            if !case_input.exists() {
+                copy_input(input, &case_input)?;
+            }
+        }
+
+        Ok(Recorded {
+            status: output.status,
+            stdout: redact(output.stdout, &substitutions),
+            stderr: redact(output.stderr, &substitutions),
+            changed,
+        })
+    }
+
+    fn write_toml(&self, case: &std::path::Path, recorded: Recorded) -> Result<(), crate::Error> {
+        let binary = [&recorded.stdout, &recorded.stderr]
+            .iter()
+            .any(|d| d.format() == snapbox::data::DataFormat::Binary);
+        let one_shot = crate::schema::OneShot {
+            bin: Some(crate::schema::Bin::Name(self.bin.clone())),
+            args: crate::schema::Args::Joined(crate::schema::JoinedArgs::from_vec(
+                self.args.clone(),
+            )),
+            env: crate::schema::Env {
+                add: self.env.clone(),
+                ..Default::default()
+            },
+            status: recorded.expected_status(),
+            binary,
+            timeout: self.timeout,
+            ..Default::default()
+        };
+
+        let raw = one_shot.to_toml()?;
+        std::fs::write(case, raw)
+            .map_err(|e| format!("Failed to write {}: {}", case.display(), e))?;
+        recorded
+            .stdout
+            .write_to_path(&case.with_extension("stdout"))?;
+        recorded
+            .stderr
+            .write_to_path(&case.with_extension("stderr"))?;
+        write_changed(&case.with_extension("out"), recorded.changed.as_deref())?;
+
+        Ok(())
+    }
+
+    fn append_markdown(
+        &self,
+        case: &std::path::Path,
+        recorded: Recorded,
+    ) -> Result<(), crate::Error> {
+        let step = crate::schema::Step {
+            bin: Some(crate::schema::Bin::Name(self.bin.clone())),
+            args: self.args.clone(),
+            env: crate::schema::Env {
+                add: self.env.clone(),
+                ..Default::default()
+            },
+            expected_status: recorded.expected_status(),
+            expected_stdout: Some(recorded.stdout),
+            ..Default::default()
+        };
+        let block = step.to_trycmd()?;
+
+        let mut raw = if case.exists() {
+            std::fs::read_to_string(case)
+                .map_err(|e| format!("Failed to read {}: {}", case.display(), e))?
+        } else {
+            String::new()
+        };
+        if !raw.is_empty() {
+            if !raw.ends_with('\n') {
+                raw.push('\n');
+            }
+            raw.push('\n');
+        }
+        raw.push_str(&block);
+        std::fs::write(case, raw)
+            .map_err(|e| format!("Failed to write {}: {}", case.display(), e))?;
+        write_changed(&case.with_extension("out"), recorded.changed.as_deref())?;
+
+        Ok(())
+    }
+}
+
+struct Recorded {
+    status: std::process::ExitStatus,
+    stdout: crate::Data,
+    stderr: crate::Data,
+    /// Files created or modified in the sandbox, if there is one
+    changed: Option<Vec<(std::path::PathBuf, Vec<u8>)>>,
+}
+
+impl Recorded {
+    fn expected_status(&self) -> Option<crate::schema::CommandStatus> {
+        if self.status.success() {
+            None
+        } else if let Some(code) = self.status.code() {
+            Some(crate::schema::CommandStatus::Code(code))
+        } else {
+            Some(crate::schema::CommandStatus::Interrupted)
+        }
+    }
+}
+
+/// Resolve `input`, failing if `target` is some other directory
+fn resolve_input(
+    input: &std::path::Path,
+    target: &std::path::Path,
+) -> Result<std::path::PathBuf, crate::Error> {
+    let input = snapbox::dir::resolve_dir(input).map_err(|e| e.to_string())?;
+    if target.exists() {
+        let target = snapbox::dir::resolve_dir(target).map_err(|e| e.to_string())?;
+        if target != input {
+            return Err(format!("{} already exists", target.display()).into());
+        }
+    }
+    Ok(input)
+}
+
+#[cfg(feature = "filesystem")]
+fn copy_input(input: &std::path::Path, target: &std::path::Path) -> Result<(), crate::Error> {
+    snapbox::dir::copy_template(input, target).map_err(|e| {
+        let _ = std::fs::remove_dir_all(target);
+        e
+    })
+}
+
+#[cfg(not(feature = "filesystem"))]
+fn copy_input(_input: &std::path::Path, _target: &std::path::Path) -> Result<(), crate::Error> {
+    Err("Sandboxing is disabled".into())
+}
+
+#[cfg(feature = "filesystem")]
+fn sandbox(input: &std::path::Path) -> Result<snapbox::dir::DirRoot, crate::Error> {
+    snapbox::dir::DirRoot::mutable_temp()?.with_template(input)
+}
+
+#[cfg(not(feature = "filesystem"))]
+fn sandbox(_input: &std::path::Path) -> Result<snapbox::dir::DirRoot, crate::Error> {
+    Err("Sandboxing is disabled".into())
+}
+
+/// Files created or modified relative to `input`, failing if any were deleted
+#[cfg(feature = "filesystem")]
+fn changed_files(
+    input: &std::path::Path,
+    root: &std::path::Path,
+) -> Result<Vec<(std::path::PathBuf, Vec<u8>)>, crate::Error> {
+    let mut deleted = Vec::new();
+    for path in snapbox::dir::Walk::new(input) {
+        let path = path.map_err(|e| e.to_string())?;
+        let rel = path.strip_prefix(input).unwrap();
+        if path.is_file() && !root.join(rel).exists() {
+            deleted.push(rel.display().to_string());
+        }
+    }
+    if !deleted.is_empty() {
+        return Err(format!(
+            "Deleted files can't be recorded in `*.out/`: {}",
+            deleted.join(", ")
+        )
+        .into());
+    }
+
+    let mut changed = Vec::new();
+    for path in snapbox::dir::Walk::new(root) {
+        let path = path.map_err(|e| e.to_string())?;
+        if !path.is_file() {
+            continue;
+        }
+        let rel = path.strip_prefix(root).unwrap().to_owned();
+        let actual = std::fs::read(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if std::fs::read(input.join(&rel)).ok().as_ref() != Some(&actual) {
+            changed.push((rel, actual));
+        }
+    }
+    Ok(changed)
+}
+
+#[cfg(not(feature = "filesystem"))]
+fn changed_files(
+    _input: &std::path::Path,
+    _root: &std::path::Path,
+) -> Result<Vec<(std::path::PathBuf, Vec<u8>)>, crate::Error> {
+    Err("Sandboxing is disabled".into())
+}
+
+/// Write `changed` files to `out`, creating it with a `.keep` if nothing changed
+fn write_changed(
+    out: &std::path::Path,
+    changed: Option<&[(std::path::PathBuf, Vec<u8>)]>,
+) -> Result<(), crate::Error> {
+    let changed = match changed {
+        Some(changed) => changed,
+        None => return Ok(()),
+    };
+    if changed.is_empty() && !out.exists() {
+        std::fs::create_dir_all(out)
+            .map_err(|e| format!("Failed to create {}: {}", out.display(), e))?;
+        let keep = out.join(".keep");
+        std::fs::write(&keep, "")
+            .map_err(|e| format!("Failed to write {}: {}", keep.display(), e))?;
+    }
+    for (rel, content) in changed {
+        let path = out.join(rel);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        std::fs::write(&path, content)
+            .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+fn redact(raw: Vec<u8>, substitutions: &snapbox::Redactions) -> crate::Data {
+    let data = crate::Data::binary(raw).coerce_to(snapbox::data::DataFormat::Text);
+    match data.render() {
+        Some(text) if data.format() == snapbox::data::DataFormat::Text => {
+            let text = snapbox::filter::normalize_paths(&snapbox::filter::normalize_lines(&text));
+            crate::Data::text(substitutions.redact(&text))
+        }
+        _ => data,
+    }
+}
@@ -23,6 +23,10 @@ impl BinRegistry {
         self.bins.extend(bins);
     }
 
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.bins.contains_key(name)
+    }
+
     pub(crate) fn resolve_bin(
         &self,
         bin: crate::schema::Bin,
@@ -3,8 +3,6 @@ use std::io::prelude::*;
 #[cfg(feature = "color")]
 use anstream::eprintln;
 #[cfg(feature = "color")]
-use anstream::panic;
-#[cfg(feature = "color")]
 use anstream::stderr;
 #[cfg(not(feature = "color"))]
 use std::eprintln;
@@ -20,34 +18,59 @@ use snapbox::IntoData;
 #[derive(Debug)]
 pub(crate) struct Runner {
     cases: Vec<Case>,
+    /// The shard, with how many cases it has out of all shards
+    shard: Option<(crate::Shard, usize, usize)>,
+    last_failed: Option<LastFailed>,
 }
 
 impl Runner {
     pub(crate) fn new() -> Self {
         Self {
             cases: Default::default(),
+            shard: None,
+            last_failed: None,
         }
     }
 
+    pub(crate) fn last_failed(&mut self, last_failed: Option<LastFailed>) {
+        self.last_failed = last_failed;
+    }
+
+    pub(crate) fn shard(&mut self, shard: crate::Shard, cases: usize, total: usize) {
+        self.shard = Some((shard, cases, total));
+    }
+
     pub(crate) fn case(&mut self, case: Case) {
         self.cases.push(case);
     }
 
+    /// Run the cases, returning a summary of any failures
     pub(crate) fn run(
         &self,
         mode: &Mode,
         bins: &crate::BinRegistry,
         substitutions: &snapbox::Redactions,
-    ) {
+    ) -> Result<(), String> {
         let palette = snapbox::report::Palette::color();
 
+        if let Some((shard, cases, total)) = self.shard {
+            eprintln!(
+                "{}",
+                palette.hint(format_args!(
+                    "Running shard {} with {} of {} cases",
+                    shard, cases, total
+                ))
+            );
+        }
+
         if self.cases.is_empty() {
             eprintln!("{}", palette.warn("There are no trycmd tests enabled yet"));
         } else {
-            let failures: Vec<_> = self
+            // Per case, as there may be several failing steps with `continue-on-error`
+            let failures: Vec<Vec<_>> = self
                 .cases
                 .par_iter()
-                .flat_map(|c| {
+                .map(|c| {
                     let results = c.run(mode, bins, substitutions);
 
                     let stderr = stderr();
@@ -66,7 +89,7 @@ impl Runner {
                                         status.name(),
                                         status.spawn.status.summary()
                                     );
-                                    if !status.is_ok() {
+                                    if !status.is_ok() || status.fs.is_kept() {
                                         // Assuming `status` will print the newline
                                         let _ = write!(stderr, "{}", &status);
                                     }
@@ -82,13 +105,28 @@ impl Runner {
                                     );
                                     // Assuming `status` will print the newline
                                     let _ = write!(stderr, "{}", &status);
-                                    Some(status)
+                                    Some((c.path.clone(), status))
                                 }
                             }
                         })
                         .collect::<Vec<_>>()
                 })
                 .collect();
+            let failed_cases = failures.iter().filter(|f| !f.is_empty()).count();
+            let failures: Vec<_> = failures.into_iter().flatten().collect();
+
+            let recorded = match self
+                .last_failed
+                .as_ref()
+                .map(|l| l.write(&self.cases, &failures))
+            {
+                Some(Ok(())) => true,
+                Some(Err(err)) => {
+                    eprintln!("{}", palette.warn(err));
+                    false
+                }
+                None => false,
+            };
 
             if !failures.is_empty() {
                 let stderr = stderr();
@@ -103,9 +141,22 @@ impl Runner {
                     "{}",
                     palette.hint("Debug output with `TRYCMD=dump`"),
                 );
-                panic!("{} of {} tests failed", failures.len(), self.cases.len());
+                if recorded {
+                    let _ = writeln!(
+                        stderr,
+                        "{}",
+                        palette.hint("Re-run only failed cases with `TRYCMD_RERUN=failed`"),
+                    );
+                }
+                return Err(format!(
+                    "{} of {} tests failed",
+                    failed_cases,
+                    self.cases.len()
+                ));
             }
         }
+
+        Ok(())
     }
 }
 
@@ -115,13 +166,22 @@ impl Default for Runner {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub(crate) struct Case {
     pub(crate) path: std::path::PathBuf,
+    pub(crate) layout: crate::schema::Layout,
     pub(crate) expected: Option<crate::schema::CommandStatus>,
     pub(crate) timeout: Option<std::time::Duration>,
     pub(crate) default_bin: Option<crate::schema::Bin>,
     pub(crate) env: crate::schema::Env,
+    pub(crate) literate: crate::schema::Literate,
+    /// Combination of the case's `[matrix]`
+    pub(crate) matrix: std::collections::BTreeMap<String, String>,
+    /// Only run the code blocks containing these lines
+    pub(crate) lines: Vec<usize>,
+    /// Expected status of the code blocks containing these lines
+    pub(crate) line_expected: std::collections::BTreeMap<usize, crate::schema::CommandStatus>,
+    pub(crate) keep_sandbox: KeepSandbox,
     pub(crate) error: Option<SpawnStatus>,
 }
 
@@ -129,14 +189,63 @@ impl Case {
     pub(crate) fn with_error(path: std::path::PathBuf, error: crate::Error) -> Self {
         Self {
             path,
+            layout: Default::default(),
             expected: None,
             timeout: None,
             default_bin: None,
             env: Default::default(),
+            literate: Default::default(),
+            matrix: Default::default(),
+            lines: Default::default(),
+            line_expected: Default::default(),
+            keep_sandbox: Default::default(),
             error: Some(SpawnStatus::Failure(error)),
         }
     }
 
+    /// Suffix for files specific to the `matrix` combination, like `format-json`
+    fn variant(&self) -> Option<String> {
+        (!self.matrix.is_empty()).then(|| {
+            self.matrix
+                .iter()
+                .map(|(name, value)| format!("{}-{}", name, value))
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+    }
+
+    /// Apply `lines` and `line_expected` to the code blocks containing them
+    fn select_blocks(&self, steps: &mut Vec<crate::schema::Step>) -> Result<(), crate::Error> {
+        let in_block = |step: &crate::schema::Step, line: usize| {
+            step.block
+                .as_ref()
+                .map(|block| block.contains(&line))
+                .unwrap_or(false)
+        };
+        for line in self.lines.iter().chain(self.line_expected.keys()) {
+            if !steps.iter().any(|step| in_block(step, *line)) {
+                return Err(format!("No code block on line {}", line).into());
+            }
+        }
+        if !self.lines.is_empty() {
+            steps.retain(|step| self.lines.iter().any(|line| in_block(step, *line)));
+        }
+        for (line, expected) in &self.line_expected {
+            for step in steps.iter_mut().filter(|step| in_block(step, *line)) {
+                step.expected_status = Some(*expected);
+            }
+        }
+        Ok(())
+    }
+
+    /// Path for reporting, distinguishing `matrix` combinations
+    fn output_path(&self) -> std::path::PathBuf {
+        match self.variant() {
+            Some(variant) => format!("{}[{}]", self.path.display(), variant).into(),
+            None => self.path.clone(),
+        }
+    }
+
     pub(crate) fn run(
         &self,
         mode: &Mode,
@@ -144,40 +253,51 @@ impl Case {
         substitutions: &snapbox::Redactions,
     ) -> Vec<Result<Output, Output>> {
         if self.expected == Some(crate::schema::CommandStatus::Skipped) {
-            let output = Output::sequence(self.path.clone());
+            let output = Output::sequence(self.output_path());
             assert_eq!(output.spawn.status, SpawnStatus::Skipped);
             return vec![Ok(output)];
         }
 
         if let Some(err) = self.error.clone() {
-            let mut output = Output::step(self.path.clone(), "setup".into());
+            let mut output = Output::step(self.output_path(), "setup".into());
             output.spawn.status = err;
             return vec![Err(output)];
         }
 
-        let mut sequence = match crate::schema::TryCmd::load(&self.path) {
+        let mut sequence = match crate::schema::TryCmd::load(
+            &self.path,
+            self.layout,
+            &self.literate,
+            self.variant().as_deref(),
+        ) {
             Ok(sequence) => sequence,
             Err(e) => {
-                let output = Output::step(self.path.clone(), "setup".into());
+                let output = Output::step(self.output_path(), "setup".into());
                 return vec![Err(output.error(e))];
             }
         };
 
+        if let Err(e) = self.select_blocks(&mut sequence.steps) {
+            let output = Output::step(self.output_path(), "setup".into());
+            return vec![Err(output.error(e))];
+        }
+
         if sequence.steps.is_empty() {
-            let output = Output::sequence(self.path.clone());
+            let output = Output::sequence(self.output_path());
             assert_eq!(output.spawn.status, SpawnStatus::Skipped);
             return vec![Ok(output)];
         }
 
         let fs_context = match fs_context(
             &self.path,
+            self.layout,
             sequence.fs.base.as_deref(),
             sequence.fs.sandbox(),
             mode,
         ) {
             Ok(fs_context) => fs_context,
             Err(e) => {
-                let output = Output::step(self.path.clone(), "setup".into());
+                let output = Output::step(self.output_path(), "setup".into());
                 return vec![Err(
                     output.error(format!("Failed to initialize sandbox: {}", e).into())
                 )];
@@ -195,7 +315,7 @@ impl Case {
         {
             Ok(cwd) => cwd.or_else(|| std::env::current_dir().ok()),
             Err(e) => {
-                let output = Output::step(self.path.clone(), "setup".into());
+                let output = Output::step(self.output_path(), "setup".into());
                 return vec![Err(output.error(e))];
             }
         };
@@ -211,15 +331,83 @@ impl Case {
             .unwrap();
         snapbox::debug!("{:?}", substitutions);
 
+        #[cfg(feature = "filesystem")]
+        let immutable = match fs_context
+            .path()
+            .filter(|root| !fs_context.is_mutable() && self.is_in_dir(root))
+        {
+            Some(root) => match DirSnapshot::new(root) {
+                Ok(snapshot) => Some((root.to_owned(), snapshot)),
+                Err(e) => {
+                    let output = Output::step(self.output_path(), "setup".into());
+                    return vec![Err(output.error(e))];
+                }
+            },
+            None => None,
+        };
+
+        let is_hermetic =
+            |step: &crate::schema::Step| step.env.hermetic.or(self.env.hermetic).unwrap_or(false);
+        let home = match fs_context.path().filter(|_| fs_context.is_mutable()) {
+            Some(root) if sequence.steps.iter().any(is_hermetic) => match hermetic_home(root) {
+                Ok(home) => {
+                    substitutions.insert("[HOME]", home.clone()).unwrap();
+                    Some(home)
+                }
+                Err(e) => {
+                    let output = Output::step(self.output_path(), "setup".into());
+                    return vec![Err(output.error(e))];
+                }
+            },
+            _ => None,
+        };
+
+        let mocks = if sequence.mocks.is_empty() {
+            None
+        } else {
+            if !fs_context.is_mutable() {
+                let output = Output::step(self.output_path(), "setup".into());
+                return vec![Err(output.error("`mocks` require a sandbox".into()))];
+            }
+            match crate::mock::Mocks::install(&sequence.mocks) {
+                Ok(mocks) => Some(mocks),
+                Err(e) => {
+                    let output = Output::step(self.output_path(), "setup".into());
+                    return vec![Err(output.error(e))];
+                }
+            }
+        };
+
+        let mut state = CaseState {
+            cwd,
+            substitutions,
+            vars: Default::default(),
+            mocks,
+        };
         let mut outputs = Vec::with_capacity(sequence.steps.len());
         let mut prior_step_failed = false;
         for step in &mut sequence.steps {
-            if prior_step_failed {
+            if prior_step_failed && !step.always_run {
                 step.expected_status = Some(crate::schema::CommandStatus::Skipped);
             }
+            if let (true, Some(home)) = (is_hermetic(step), &home) {
+                for (name, dir) in crate::schema::HERMETIC_HOME {
+                    step.env
+                        .add
+                        .entry((*name).to_owned())
+                        .or_insert_with(|| match *dir {
+                            "" => home.display().to_string(),
+                            dir => home.join(dir).display().to_string(),
+                        });
+                }
+            }
 
-            let step_status = self.run_step(step, cwd.as_deref(), bins, &substitutions);
-            if fs_context.is_mutable() && step_status.is_err() && *mode == Mode::Fail {
+            let step_status = self.run_step(step, &fs_context, bins, &mut state);
+            if fs_context.is_mutable()
+                && step_status.is_err()
+                && *mode == Mode::Fail
+                && !step.continue_on_error
+            {
                 prior_step_failed = true;
             }
             outputs.push(step_status);
@@ -241,18 +429,45 @@ impl Case {
                             Ok(stream) => stream,
                             Err(stream) => stream,
                         };
+                    output.mocks =
+                        match self.dump_stream(root, output.id.as_deref(), output.mocks.take()) {
+                            Ok(stream) => stream,
+                            Err(stream) => stream,
+                        };
                 }
             }
             Mode::Overwrite => {
                 // `rev()` to ensure we don't mess up our line number info
                 for step_status in outputs.iter_mut().rev() {
                     if let Err(output) = step_status {
+                        let files = output
+                            .fs
+                            .context
+                            .iter()
+                            .filter_map(|status| match status {
+                                FileStatus::ContentMismatch {
+                                    expected_path,
+                                    actual_content,
+                                    ..
+                                } => Some((
+                                    expected_path.to_string_lossy().into_owned(),
+                                    actual_content.clone(),
+                                )),
+                                _ => None,
+                            })
+                            .collect();
                         let res = sequence.overwrite(
                             &self.path,
+                            self.layout,
                             output.id.as_deref(),
-                            output.stdout.as_ref().map(|s| &s.content),
-                            output.stderr.as_ref().map(|s| &s.content),
-                            output.spawn.exit,
+                            self.variant().as_deref(),
+                            crate::schema::Actual {
+                                stdout: output.stdout.as_ref().map(|s| &s.content),
+                                stderr: output.stderr.as_ref().map(|s| &s.content),
+                                exit: output.spawn.exit,
+                                files: &files,
+                                mocks: output.mocks.as_ref().map(|s| &s.content),
+                            },
                         );
 
                         if res.is_ok() {
@@ -266,21 +481,44 @@ impl Case {
 
         if sequence.fs.sandbox() {
             let mut ok = true;
-            let mut output = Output::step(self.path.clone(), "teardown".into());
-
-            output.fs = match self.validate_fs(
-                fs_context.path().expect("sandbox must be filled"),
-                output.fs,
-                mode,
-                &substitutions,
-            ) {
-                Ok(fs) => fs,
-                Err(fs) => {
-                    ok = false;
-                    fs
-                }
+            let mut output = Output::step(self.output_path(), "teardown".into());
+
+            // `*.out/` is for all of the code blocks
+            if self.lines.is_empty() {
+                output.fs = match self.validate_fs(
+                    fs_context.path().expect("sandbox must be filled"),
+                    output.fs,
+                    mode,
+                    &state.substitutions,
+                ) {
+                    Ok(fs) => fs,
+                    Err(fs) => {
+                        ok = false;
+                        fs
+                    }
+                };
+            }
+            let failed = !ok || outputs.iter().any(|o| o.is_err());
+            let keep = match self.keep_sandbox {
+                KeepSandbox::Never => false,
+                KeepSandbox::Failed => failed,
+                KeepSandbox::All => true,
             };
-            if let Err(err) = fs_context.close() {
+            if keep {
+                let path = fs_context.keep().expect("sandbox must be filled");
+                let failure = outputs.iter().find_map(|o| o.as_ref().err());
+                match failure
+                    .and_then(|f| f.command.as_ref().map(|c| (f, c)))
+                    .map(|(failure, command)| write_rerun_script(&path, failure, command))
+                    .transpose()
+                {
+                    Ok(script) => output.fs.context.push(FileStatus::Kept { path, script }),
+                    Err(err) => {
+                        ok = false;
+                        output.fs.context.push(FileStatus::Failure(err));
+                    }
+                }
+            } else if let Err(err) = fs_context.close() {
                 ok = false;
                 output.fs.context.push(FileStatus::Failure(
                     format!("Failed to cleanup sandbox: {}", err).into(),
@@ -297,6 +535,26 @@ impl Case {
             outputs.push(output);
         }
 
+        #[cfg(feature = "filesystem")]
+        if let Some((root, before)) = immutable {
+            let context = match DirSnapshot::new(&root) {
+                Ok(after) => before.changes(&after),
+                Err(e) => vec![FileStatus::Failure(e)],
+            };
+            if !context.is_empty() {
+                let mut output = Output::step(self.output_path(), "teardown".into());
+                output.fs.context = context;
+                output.spawn.status = SpawnStatus::Failure(
+                    format!(
+                        "{} was modified; use `fs.sandbox = true` to write to it",
+                        root.display()
+                    )
+                    .into(),
+                );
+                outputs.push(Err(output));
+            }
+        }
+
         outputs
     }
 
@@ -304,16 +562,68 @@ impl Case {
     pub(crate) fn run_step(
         &self,
         step: &mut crate::schema::Step,
-        cwd: Option<&std::path::Path>,
+        fs_context: &snapbox::dir::DirRoot,
         bins: &crate::BinRegistry,
-        substitutions: &snapbox::Redactions,
+        state: &mut CaseState,
     ) -> Result<Output, Output> {
+        let CaseState {
+            cwd,
+            substitutions,
+            vars,
+            mocks,
+        } = state;
+        let mocks = mocks.as_ref();
         let output = if let Some(id) = step.id.clone() {
-            Output::step(self.path.clone(), id)
+            Output::step(self.output_path(), id)
         } else {
-            Output::sequence(self.path.clone())
+            Output::sequence(self.output_path())
         };
 
+        let expected = self.expected.or(step.expected_status).unwrap_or_default();
+        let mut pipeline = None;
+        if let (Some(cmdline), false) = (
+            &step.shell_cmdline,
+            expected == crate::schema::CommandStatus::Skipped,
+        ) {
+            let mut env = step.env.clone();
+            env.update(&self.env);
+            let timeout = step.timeout.or(self.timeout);
+            let cx = crate::shell::Context {
+                env: &env,
+                root: fs_context.path(),
+                cwd: cwd.as_deref(),
+                sandbox: fs_context.is_mutable(),
+                bins,
+                timeout,
+            };
+            let mut expanded = cmdline.expand(&cx).map_err(|e| output.clone().error(e))?;
+            let last = expanded.pop().expect("parsing ensures a command");
+            step.env.add.extend(last.env);
+            step.bin = Some(crate::schema::Bin::Name(last.bin));
+            step.args = last.args;
+            if !expanded.is_empty() || !last.redirects.is_empty() {
+                let upstream = expanded
+                    .into_iter()
+                    .map(|e| {
+                        let mut env = env.clone();
+                        env.add.extend(e.env);
+                        let upstream = crate::schema::Step {
+                            bin: Some(bins.resolve_name(&e.bin)),
+                            args: e.args,
+                            env,
+                            timeout,
+                            ..Default::default()
+                        };
+                        (upstream, e.redirects)
+                    })
+                    .collect();
+                pipeline = Some(crate::shell::Pipeline {
+                    upstream,
+                    redirects: last.redirects,
+                });
+            }
+        }
+
         let mut bin = step.bin.take();
         if bin.is_none() {
             bin.clone_from(&self.default_bin);
@@ -336,33 +646,91 @@ impl Case {
             return Ok(output);
         }
 
-        match &step.bin {
-            Some(crate::schema::Bin::Path(_)) => {}
-            Some(crate::schema::Bin::Name(_name)) => {
-                // Unhandled by resolve
-                snapbox::debug!("bin={:?} not found", _name);
-                assert_eq!(output.spawn.status, SpawnStatus::Skipped);
-                return Ok(output);
+        self.interpolate(step, vars);
+        if let Some(pipeline) = &mut pipeline {
+            for (upstream, _) in &mut pipeline.upstream {
+                self.interpolate(upstream, vars);
+            }
+        }
+
+        self.write_files(step, fs_context, cwd.as_deref())
+            .map_err(|e| output.clone().error(e))?;
+        if let Some(mocks) = mocks {
+            mocks.reset().map_err(|e| output.clone().error(e))?;
+            mocks
+                .prepend_path(&mut step.env)
+                .map_err(|e| output.clone().error(e))?;
+            for (upstream, _) in pipeline.iter_mut().flat_map(|p| p.upstream.iter_mut()) {
+                mocks
+                    .prepend_path(&mut upstream.env)
+                    .map_err(|e| output.clone().error(e))?;
             }
-            Some(crate::schema::Bin::Error(_)) => {}
-            // Unlike `Name`, this always represents a bug
-            None => {}
-            Some(crate::schema::Bin::Ignore) => {
-                // Unhandled by resolve
-                assert_eq!(output.spawn.status, SpawnStatus::Skipped);
-                return Ok(output);
+        }
+
+        if let (None, Some(crate::schema::Bin::Name(name))) = (&pipeline, &step.bin) {
+            if name == "cd" {
+                return self.cd(output, step, fs_context.path(), cwd, substitutions);
+            }
+        }
+
+        let upstream_bins = pipeline
+            .iter()
+            .flat_map(|p| p.upstream.iter().map(|(s, _)| &s.bin));
+        for bin in std::iter::once(&step.bin).chain(upstream_bins) {
+            match bin {
+                Some(crate::schema::Bin::Path(_)) => {}
+                Some(crate::schema::Bin::Name(_name)) => {
+                    // Unhandled by resolve
+                    snapbox::debug!("bin={:?} not found", _name);
+                    assert_eq!(output.spawn.status, SpawnStatus::Skipped);
+                    return Ok(output);
+                }
+                Some(crate::schema::Bin::Error(_)) => {}
+                // Unlike `Name`, this always represents a bug
+                None => {}
+                Some(crate::schema::Bin::Ignore) => {
+                    // Unhandled by resolve
+                    assert_eq!(output.spawn.status, SpawnStatus::Skipped);
+                    return Ok(output);
+                }
             }
         }
 
-        let cmd = step.to_command(cwd).map_err(|e| output.clone().error(e))?;
-        let cmd_output = cmd
-            .output()
-            .map_err(|e| output.clone().error(e.to_string().into()))?;
+        let output = output.command(reproduction(step, pipeline.as_ref(), cwd.as_deref()));
+        let cmd_output = match &pipeline {
+            Some(pipeline) => pipeline
+                .output(step, cwd.as_deref())
+                .map_err(|e| output.clone().error(e))?,
+            None => {
+                let cmd = step
+                    .to_command(cwd.as_deref())
+                    .map_err(|e| output.clone().error(e))?;
+                cmd.output()
+                    .map_err(|e| output.clone().error(e.to_string().into()))?
+            }
+        };
+        for capture in &step.captures {
+            let value = capture
+                .extract(&cmd_output.stdout)
+                .and_then(|value| {
+                    let placeholder = capture.placeholder()?;
+                    substitutions.remove(placeholder)?;
+                    substitutions.insert(placeholder, value.clone())?;
+                    Ok(value)
+                })
+                .map_err(|e| output.clone().output(cmd_output.clone()).error(e))?;
+            vars.insert(capture.name.clone(), value);
+        }
         let output = output.output(cmd_output);
 
         // For Mode::Dump's sake, allow running all
         let output = self.validate_spawn(output, step.expected_status());
         let output = self.validate_streams(output, step, substitutions);
+        let output = self.validate_files(output, step, fs_context, cwd.as_deref(), substitutions);
+        let output = match mocks {
+            Some(mocks) => self.validate_mocks(output, step, mocks, substitutions),
+            None => output,
+        };
 
         if output.is_ok() {
             Ok(output)
@@ -371,6 +739,76 @@ impl Case {
         }
     }
 
+    /// Replace `{{name}}` with the `matrix` combination and `[NAME]` with captured variables
+    fn interpolate(
+        &self,
+        step: &mut crate::schema::Step,
+        vars: &std::collections::BTreeMap<String, String>,
+    ) {
+        let replacements = self
+            .matrix
+            .iter()
+            .map(|(name, value)| (format!("{{{{{}}}}}", name), value))
+            .chain(
+                vars.iter()
+                    .map(|(name, value)| (format!("[{}]", name), value)),
+            )
+            .collect::<Vec<_>>();
+        if replacements.is_empty() {
+            return;
+        }
+        let apply = |s: &str| {
+            replacements
+                .iter()
+                .fold(s.to_owned(), |s, (pattern, value)| {
+                    s.replace(pattern, value)
+                })
+        };
+        for arg in &mut step.args {
+            *arg = apply(arg);
+        }
+        for value in step.env.add.values_mut() {
+            *value = apply(value);
+        }
+        if let Some(stdin) = &step.stdin {
+            if stdin.format() == DataFormat::Text {
+                if let Some(text) = stdin.render() {
+                    step.stdin = Some(crate::Data::text(apply(&text)));
+                }
+            }
+        }
+    }
+
+    /// Built-in `cd`, changing the directory for later steps within the sandbox
+    #[allow(clippy::result_large_err)]
+    fn cd(
+        &self,
+        mut output: Output,
+        step: &crate::schema::Step,
+        root: Option<&std::path::Path>,
+        cwd: &mut Option<std::path::PathBuf>,
+        substitutions: &mut snapbox::Redactions,
+    ) -> Result<Output, Output> {
+        let dir = match step.args.as_slice() {
+            [dir] => dir,
+            _ => return Err(output.error("`cd` expects one directory".into())),
+        };
+        let root = match root {
+            Some(root) => root,
+            None => return Err(output.error("`cd` requires a sandbox".into())),
+        };
+        let target = crate::shell::resolve(root, cwd.as_deref().unwrap_or(root), dir)
+            .map_err(|e| output.clone().error(e))?;
+        if !target.is_dir() {
+            return Err(output.error(format!("`{}` is not a directory", dir).into()));
+        }
+        substitutions.remove("[CWD]").unwrap();
+        substitutions.insert("[CWD]", target.clone()).unwrap();
+        *cwd = Some(target);
+        output.spawn.status = SpawnStatus::Ok;
+        Ok(output)
+    }
+
     fn validate_spawn(&self, mut output: Output, expected: crate::schema::CommandStatus) -> Output {
         let status = output.spawn.exit.expect("bale out before now");
         match expected {
@@ -459,24 +897,32 @@ impl Case {
         stream: Option<Stream>,
     ) -> Result<Option<Stream>, Option<Stream>> {
         if let Some(stream) = stream {
-            let file_name = match id {
-                Some(id) => {
-                    format!(
-                        "{}-{}.{}",
-                        self.path.file_stem().unwrap().to_string_lossy(),
-                        id,
-                        stream.stream.as_str(),
-                    )
-                }
-                None => {
-                    format!(
-                        "{}.{}",
-                        self.path.file_stem().unwrap().to_string_lossy(),
-                        stream.stream.as_str(),
-                    )
+            // Case directories dump `stdout` rather than `<stem>.stdout`
+            let mut stem = if self.layout == crate::schema::Layout::Dir {
+                String::new()
+            } else {
+                self.path
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            };
+            let join = |stem: String, sep: &str, part: &str| {
+                if stem.is_empty() {
+                    part.to_owned()
+                } else {
+                    format!("{}{}{}", stem, sep, part)
                 }
             };
-            let stream_path = root.join(file_name);
+            if let Some(variant) = self.variant() {
+                stem = join(stem, ".", &variant);
+            }
+            if let Some(id) = id {
+                // Included steps have ids like `12:3`
+                stem = join(stem, "-", &id.replace(':', "-"));
+            }
+            let file_name = join(stem, ".", stream.stream.as_str());
+            let stream_path = dump_dir(root, &self.path, self.layout).join(file_name);
             stream.content.write_to_path(&stream_path).map_err(|e| {
                 let mut stream = stream.clone();
                 if stream.is_ok() {
@@ -490,6 +936,118 @@ impl Case {
         }
     }
 
+    /// Record calls to `mocks` as another stream
+    fn validate_mocks(
+        &self,
+        mut output: Output,
+        step: &crate::schema::Step,
+        mocks: &crate::mock::Mocks,
+        substitutions: &snapbox::Redactions,
+    ) -> Output {
+        let stream = match mocks.calls() {
+            Ok(calls) => Stream {
+                stream: Stdio::Mocks,
+                content: calls.into_data(),
+                status: StreamStatus::Ok,
+            },
+            Err(e) => Stream {
+                stream: Stdio::Mocks,
+                content: crate::Data::new(),
+                status: StreamStatus::Failure(e),
+            },
+        };
+        output.mocks = self.validate_stream(
+            Some(stream),
+            step.expected_mocks.as_ref(),
+            false,
+            substitutions,
+        );
+        output
+    }
+
+    /// Write `file=` blocks into the sandbox
+    fn write_files(
+        &self,
+        step: &crate::schema::Step,
+        fs_context: &snapbox::dir::DirRoot,
+        cwd: Option<&std::path::Path>,
+    ) -> Result<(), crate::Error> {
+        for file in &step.files {
+            let root = fs_context
+                .path()
+                .filter(|_| fs_context.is_mutable())
+                .ok_or_else(|| format!("`file={}` requires a sandbox", file.path))?;
+            let path = crate::shell::resolve(root, cwd.unwrap_or(root), &file.path)?;
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+            }
+            std::fs::write(&path, &file.content)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    /// Compare `expect-file=` blocks against the sandbox
+    fn validate_files(
+        &self,
+        mut output: Output,
+        step: &crate::schema::Step,
+        fs_context: &snapbox::dir::DirRoot,
+        cwd: Option<&std::path::Path>,
+        substitutions: &snapbox::Redactions,
+    ) -> Output {
+        for file in &step.expected_files {
+            let status = fs_context
+                .path()
+                .ok_or_else(|| format!("`expect-file={}` requires a sandbox", file.path).into())
+                .and_then(|root| crate::shell::resolve(root, cwd.unwrap_or(root), &file.path))
+                .and_then(|actual_path| {
+                    let actual = std::fs::read_to_string(&actual_path)
+                        .map_err(|e| format!("Failed to read {}: {}", actual_path.display(), e))?;
+                    Ok((actual_path, actual))
+                });
+            let status = match status {
+                Ok((actual_path, actual)) => {
+                    // Like `stdout`, a trailing newline is for formatting the block
+                    let trim = |s: &str| s.strip_suffix('\n').unwrap_or(s).to_owned();
+                    let expected_content = crate::Data::text(trim(&file.content));
+                    let actual =
+                        FilterNewlines.filter(FilterPaths.filter(crate::Data::text(actual)));
+                    let actual = crate::Data::text(trim(&actual.render().unwrap_or_default()));
+                    let actual_content = NormalizeToExpected::new()
+                        .redact_with(substitutions)
+                        .normalize(actual, &expected_content);
+                    if actual_content == expected_content {
+                        FileStatus::Ok {
+                            expected_path: file.path.clone().into(),
+                            actual_path,
+                        }
+                    } else {
+                        FileStatus::ContentMismatch {
+                            expected_path: file.path.clone().into(),
+                            actual_path,
+                            expected_content,
+                            actual_content,
+                        }
+                    }
+                }
+                Err(err) => FileStatus::Failure(err),
+            };
+            output.fs.context.push(status);
+        }
+        output
+    }
+
+    /// Whether `root` is an input directory, like `*.in/` or `in/`, which only a sandbox may modify
+    #[cfg(feature = "filesystem")]
+    fn is_in_dir(&self, root: &std::path::Path) -> bool {
+        root.extension() == Some(std::ffi::OsStr::new("in"))
+            || snapbox::dir::resolve_dir(self.layout.sibling(&self.path, "in"))
+                .map(|dir| dir == root)
+                .unwrap_or(false)
+    }
+
     fn validate_fs(
         &self,
         actual_root: &std::path::Path,
@@ -503,7 +1061,7 @@ impl Case {
         if let Mode::Dump(_) = mode {
             // Handled as part of DirRoot
         } else {
-            let fixture_root = self.path.with_extension("out");
+            let fixture_root = self.layout.sibling(&self.path, "out");
             if fixture_root.exists() {
                 for status in snapbox::dir::PathDiff::subset_matches_iter(
                     fixture_root,
@@ -540,6 +1098,16 @@ impl Case {
     }
 }
 
+/// What the steps of a [`Case`] pass along to each other
+pub(crate) struct CaseState {
+    /// Changed by `cd`
+    cwd: Option<std::path::PathBuf>,
+    substitutions: snapbox::Redactions,
+    /// Captured values, by name
+    vars: std::collections::BTreeMap<String, String>,
+    mocks: Option<crate::mock::Mocks>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) struct Output {
     path: std::path::PathBuf,
@@ -547,7 +1115,10 @@ pub(crate) struct Output {
     spawn: Spawn,
     stdout: Option<Stream>,
     stderr: Option<Stream>,
+    mocks: Option<Stream>,
     fs: Filesystem,
+    /// `/bin/sh` snippet that runs the step outside of the harness
+    command: Option<String>,
 }
 
 impl Output {
@@ -561,7 +1132,9 @@ impl Output {
             },
             stdout: None,
             stderr: None,
+            mocks: None,
             fs: Default::default(),
+            command: None,
         }
     }
 
@@ -572,10 +1145,17 @@ impl Output {
             spawn: Default::default(),
             stdout: None,
             stderr: None,
+            mocks: None,
             fs: Default::default(),
+            command: None,
         }
     }
 
+    fn command(mut self, command: String) -> Self {
+        self.command = Some(command);
+        self
+    }
+
     fn output(mut self, output: std::process::Output) -> Self {
         self.spawn.exit = Some(output.status);
         assert_eq!(self.spawn.status, SpawnStatus::Skipped);
@@ -602,6 +1182,7 @@ impl Output {
         self.spawn.is_ok()
             && self.stdout.as_ref().map(|s| s.is_ok()).unwrap_or(true)
             && self.stderr.as_ref().map(|s| s.is_ok()).unwrap_or(true)
+            && self.mocks.as_ref().map(|s| s.is_ok()).unwrap_or(true)
             && self.fs.is_ok()
     }
 
@@ -622,7 +1203,14 @@ impl std::fmt::Display for Output {
         if let Some(stderr) = &self.stderr {
             stderr.fmt(f)?;
         }
+        if let Some(mocks) = &self.mocks {
+            mocks.fmt(f)?;
+        }
         self.fs.fmt(f)?;
+        if let (false, Some(command)) = (self.is_ok(), &self.command) {
+            writeln!(f, "Reproduce with:")?;
+            write!(f, "{}", command)?;
+        }
 
         Ok(())
     }
@@ -798,6 +1386,8 @@ impl StreamStatus {
 enum Stdio {
     Stdout,
     Stderr,
+    /// Calls to `mocks`
+    Mocks,
 }
 
 impl Stdio {
@@ -805,6 +1395,7 @@ impl Stdio {
         match self {
             Self::Stdout => "stdout",
             Self::Stderr => "stderr",
+            Self::Mocks => "mocks",
         }
     }
 }
@@ -828,6 +1419,12 @@ impl Filesystem {
             self.context.iter().all(FileStatus::is_ok)
         }
     }
+
+    fn is_kept(&self) -> bool {
+        self.context
+            .iter()
+            .any(|status| matches!(status, FileStatus::Kept { .. }))
+    }
 }
 
 impl std::fmt::Display for Filesystem {
@@ -865,12 +1462,17 @@ enum FileStatus {
         expected_content: crate::Data,
         actual_content: crate::Data,
     },
+    /// Sandbox left behind by `TRYCMD_KEEP_SANDBOX`
+    Kept {
+        path: std::path::PathBuf,
+        script: Option<std::path::PathBuf>,
+    },
 }
 
 impl FileStatus {
     fn is_ok(&self) -> bool {
         match self {
-            Self::Ok { .. } => true,
+            Self::Ok { .. } | Self::Kept { .. } => true,
             Self::Failure(_)
             | Self::TypeMismatch { .. }
             | Self::LinkMismatch { .. }
@@ -939,6 +1541,16 @@ impl std::fmt::Display for FileStatus {
             Self::Failure(msg) => {
                 writeln!(f, "{}", palette.error(msg))?;
             }
+            Self::Kept { path, script } => {
+                writeln!(f, "Sandbox kept at {}", palette.info(path.display()))?;
+                if let Some(script) = script {
+                    writeln!(
+                        f,
+                        "Re-run the failed step with `sh {}`",
+                        palette.info(script.display())
+                    )?;
+                }
+            }
             Self::TypeMismatch {
                 expected_path,
                 actual_path: _actual_path,
@@ -988,6 +1600,93 @@ impl std::fmt::Display for FileStatus {
     }
 }
 
+/// Cases that failed in the last run, for `TRYCMD_RERUN=failed`
+///
+/// Each line is a case's path.  Only runs from a cargo target directory are recorded, one file per
+/// test binary and `#[test]`.  Outside of a `#[test]`, like with the `trycmd` binary, runs are told
+/// apart by their directory and the case globs.
+#[derive(Debug)]
+pub(crate) struct LastFailed {
+    path: std::path::PathBuf,
+}
+
+impl LastFailed {
+    pub(crate) fn new(globs: &[&std::path::Path]) -> Option<Self> {
+        let exe = std::env::current_exe().ok()?;
+        let dir = exe.parent()?;
+        let profile = if dir.ends_with("deps") {
+            dir.parent()?
+        } else if dir.join("deps").is_dir() {
+            dir
+        } else {
+            return None;
+        };
+        let mut name = exe.file_stem()?.to_string_lossy().into_owned();
+        // libtest names threads after the `#[test]`
+        if let Some(test) = std::thread::current().name().filter(|n| *n != "main") {
+            name.push('.');
+            name.push_str(&test.replace("::", "."));
+        } else {
+            let mut key = std::env::current_dir().ok()?.into_os_string();
+            for glob in globs {
+                key.push("\0");
+                key.push(glob);
+            }
+            let hash = crate::spec::fnv1a(key.to_string_lossy().as_bytes());
+            name.push_str(&format!(".{:016x}", hash));
+        }
+        let path = profile.join("trycmd-last-failed").join(name);
+        Some(Self { path })
+    }
+
+    /// Paths of the failed cases, empty if none failed or nothing was recorded
+    pub(crate) fn read(&self) -> std::collections::BTreeSet<std::path::PathBuf> {
+        std::fs::read_to_string(&self.path)
+            .unwrap_or_default()
+            .lines()
+            .filter(|path| !path.is_empty())
+            .map(std::path::PathBuf::from)
+            .collect()
+    }
+
+    /// Replace the record for each case in `cases` that fully ran, keeping the rest
+    fn write(
+        &self,
+        cases: &[Case],
+        failures: &[(std::path::PathBuf, Output)],
+    ) -> Result<(), crate::Error> {
+        let mut failed = self.read();
+        for case in cases {
+            // Other code blocks in the case may still be failing
+            if case.lines.is_empty() {
+                failed.remove(&case.path);
+            }
+        }
+        failed.extend(failures.iter().map(|(path, _)| path.clone()));
+
+        let mut lines = String::new();
+        for path in failed {
+            lines.push_str(&path.to_string_lossy());
+            lines.push('\n');
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        std::fs::write(&self.path, lines)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
+
+/// When to leave a sandbox behind for debugging, from `TRYCMD_KEEP_SANDBOX`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) enum KeepSandbox {
+    #[default]
+    Never,
+    Failed,
+    All,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub(crate) enum Mode {
     Fail,
@@ -1011,9 +1710,174 @@ impl Mode {
     }
 }
 
+/// `/bin/sh` snippet to run `step` like the harness does
+fn reproduction(
+    step: &crate::schema::Step,
+    pipeline: Option<&crate::shell::Pipeline>,
+    cwd: Option<&std::path::Path>,
+) -> String {
+    let quote = crate::shell::quote;
+    let mut script = String::new();
+    if let Some(cwd) = cwd
+        .map(|cwd| cwd.to_owned())
+        .or_else(|| std::env::current_dir().ok())
+    {
+        script.push_str(&format!("cd {}\n", quote(&cwd.to_string_lossy())));
+    }
+
+    let mut stdin = None;
+    let mut printf = None;
+    if let Some(data) = &step.stdin {
+        match data.source().and_then(|s| s.as_path()) {
+            Some(path) => {
+                let path = std::env::current_dir()
+                    .map(|dir| dir.join(path))
+                    .unwrap_or_else(|_| path.to_owned());
+                stdin = Some(format!("< {}", quote(&path.to_string_lossy())));
+            }
+            // Piped in, rather than a heredoc, to keep it byte-for-byte
+            None => match data
+                .render()
+                .and_then(|text| shlex::try_quote(&text).ok().map(|q| q.into_owned()))
+            {
+                Some(text) => printf = Some(vec!["printf".to_owned(), "'%s'".to_owned(), text]),
+                None => script.push_str("# binary `stdin` can't be shown\n"),
+            },
+        }
+    }
+
+    let mut stages = Vec::new();
+    stages.extend(printf);
+    for (upstream, redirects) in pipeline.iter().flat_map(|p| p.upstream.iter()) {
+        let mut words = upstream.to_shell_words();
+        words.extend(redirects.to_shell_words());
+        stages.push(words);
+    }
+    let mut words = step.to_shell_words();
+    match pipeline.filter(|p| !p.redirects.is_empty()) {
+        Some(pipeline) => words.extend(pipeline.redirects.to_shell_words()),
+        None if step.stderr_to_stdout => words.push("2>&1".to_owned()),
+        None => {}
+    }
+    stages.push(words);
+    stages[0].extend(stdin);
+    let stages = stages.iter().map(|w| w.join(" ")).collect::<Vec<_>>();
+    script.push_str(&stages.join(" | "));
+    script.push('\n');
+    script
+}
+
+/// Write a script into a kept sandbox that re-runs `failure`
+fn write_rerun_script(
+    root: &std::path::Path,
+    failure: &Output,
+    command: &str,
+) -> Result<std::path::PathBuf, crate::Error> {
+    let path = root.join("trycmd-rerun.sh");
+    let script = format!("#!/bin/sh\n# Re-run {}\n{}", failure.name(), command);
+    std::fs::write(&path, script)
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// State of each file under a directory, to detect changes to it
+#[cfg(feature = "filesystem")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct DirSnapshot(std::collections::BTreeMap<std::path::PathBuf, FileState>);
+
+#[cfg(feature = "filesystem")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum FileState {
+    Dir,
+    File { len: u64, hash: u64 },
+    Symlink(std::path::PathBuf),
+    Other,
+}
+
+#[cfg(feature = "filesystem")]
+impl DirSnapshot {
+    fn new(root: &std::path::Path) -> Result<Self, crate::Error> {
+        use std::hash::{Hash as _, Hasher as _};
+
+        let mut files = std::collections::BTreeMap::new();
+        for path in snapbox::dir::Walk::new(root) {
+            let path = path.map_err(|e| format!("Failed to read {}: {}", root.display(), e))?;
+            let metadata = path
+                .symlink_metadata()
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let state = if metadata.is_dir() {
+                FileState::Dir
+            } else if metadata.is_symlink() {
+                let target = std::fs::read_link(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                FileState::Symlink(target)
+            } else if metadata.is_file() {
+                let content = std::fs::read(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                content.hash(&mut hasher);
+                FileState::File {
+                    len: metadata.len(),
+                    hash: hasher.finish(),
+                }
+            } else {
+                FileState::Other
+            };
+            let rel = path.strip_prefix(root).unwrap_or(&path).to_owned();
+            files.insert(rel, state);
+        }
+        Ok(Self(files))
+    }
+
+    /// Files created, modified, or deleted since `self`
+    fn changes(&self, after: &Self) -> Vec<FileStatus> {
+        let mut changes = Vec::new();
+        for (path, state) in &self.0 {
+            match after.0.get(path) {
+                Some(after) if after == state => {}
+                Some(_) => changes.push(format!("{}: modified", path.display())),
+                None => changes.push(format!("{}: deleted", path.display())),
+            }
+        }
+        for path in after.0.keys() {
+            if !self.0.contains_key(path) {
+                changes.push(format!("{}: created", path.display()));
+            }
+        }
+        changes
+            .into_iter()
+            .map(|change| FileStatus::Failure(change.into()))
+            .collect()
+    }
+}
+
+/// Create `home/` in the sandbox for hermetic cases, so `*.in/home/` can seed it
+fn hermetic_home(root: &std::path::Path) -> Result<std::path::PathBuf, crate::Error> {
+    let home = root.join("home");
+    for (_, dir) in crate::schema::HERMETIC_HOME {
+        let dir = home.join(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    }
+    Ok(home)
+}
+
+/// Where `TRYCMD=dump` writes a case's files, mirroring case directories
+fn dump_dir(
+    root: &std::path::Path,
+    path: &std::path::Path,
+    layout: crate::schema::Layout,
+) -> std::path::PathBuf {
+    match path.parent().and_then(|dir| dir.file_name()) {
+        Some(dir) if layout == crate::schema::Layout::Dir => root.join(dir),
+        _ => root.to_owned(),
+    }
+}
+
 #[cfg_attr(not(feature = "filesystem"), allow(unused_variables))]
 fn fs_context(
     path: &std::path::Path,
+    layout: crate::schema::Layout,
     cwd: Option<&std::path::Path>,
     sandbox: bool,
     mode: &Mode,
@@ -1022,7 +1886,8 @@ fn fs_context(
         #[cfg(feature = "filesystem")]
         match mode {
             Mode::Dump(root) => {
-                let target = root.join(path.with_extension("out").file_name().unwrap());
+                let out = layout.sibling(path, "out");
+                let target = dump_dir(root, path, layout).join(out.file_name().unwrap());
                 let mut context = snapbox::dir::DirRoot::mutable_at(&target)?;
                 if let Some(cwd) = cwd {
                     context = context.with_template(cwd)?;
@@ -10,12 +10,26 @@ use std::collections::VecDeque;
 pub(crate) struct TryCmd {
     pub(crate) steps: Vec<Step>,
     pub(crate) fs: Filesystem,
+    pub(crate) literate: Literate,
+    pub(crate) mocks: BTreeMap<String, Mock>,
 }
 
 impl TryCmd {
-    pub(crate) fn load(path: &std::path::Path) -> Result<Self, crate::Error> {
+    /// Load a case, preferring `name.<variant>.stdout` and similar files when given a `variant`
+    pub(crate) fn load(
+        path: &std::path::Path,
+        layout: Layout,
+        literate: &Literate,
+        variant: Option<&str>,
+    ) -> Result<Self, crate::Error> {
         let mut sequence = if let Some(ext) = path.extension() {
             if ext == std::ffi::OsStr::new("toml") {
+                let with_extension = |ext: &str| {
+                    variant
+                        .map(|variant| layout.sibling(path, &format!("{}.{}", variant, ext)))
+                        .filter(|p| p.exists())
+                        .unwrap_or_else(|| layout.sibling(path, ext))
+                };
                 let raw = std::fs::read_to_string(path)
                     .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
                 let one_shot = OneShot::parse_toml(&raw)?;
@@ -26,7 +40,7 @@ impl TryCmd {
                 };
 
                 if sequence.steps[0].stdin.is_none() {
-                    let stdin_path = path.with_extension("stdin");
+                    let stdin_path = with_extension("stdin");
                     let stdin = if stdin_path.exists() {
                         // No `map_text` as we will trust what the user inputted
                         Some(crate::Data::try_read_from(&stdin_path, Some(is_binary))?)
@@ -37,7 +51,7 @@ impl TryCmd {
                 }
 
                 if sequence.steps[0].expected_stdout.is_none() {
-                    let stdout_path = path.with_extension("stdout");
+                    let stdout_path = with_extension("stdout");
                     let stdout = if stdout_path.exists() {
                         Some(
                             FilterNewlines.filter(
@@ -52,7 +66,7 @@ impl TryCmd {
                 }
 
                 if sequence.steps[0].expected_stderr.is_none() {
-                    let stderr_path = path.with_extension("stderr");
+                    let stderr_path = with_extension("stderr");
                     let stderr = if stderr_path.exists() {
                         Some(
                             FilterNewlines.filter(
@@ -66,12 +80,43 @@ impl TryCmd {
                     sequence.steps[0].expected_stderr = stderr;
                 }
 
+                let mocks_path = with_extension("mocks");
+                if mocks_path.exists() {
+                    sequence.steps[0].expected_mocks = Some(FilterNewlines.filter(
+                        FilterPaths.filter(crate::Data::read_from(&mocks_path, Some(is_binary))),
+                    ));
+                }
+
                 sequence
-            } else if ext == std::ffi::OsStr::new("trycmd") || ext == std::ffi::OsStr::new("md") {
+            } else if is_literate(ext) {
                 let raw = std::fs::read_to_string(path)
                     .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
-                let normalized = snapbox::filter::normalize_lines(&raw);
-                Self::parse_trycmd(&normalized)?
+                let mut normalized = snapbox::filter::normalize_lines(&raw);
+                if ext == std::ffi::OsStr::new("rs") {
+                    normalized = extract_doc_comments(&normalized);
+                }
+                let (front_matter, normalized) = FrontMatter::split(&normalized)?;
+                let mut literate = literate.clone();
+                literate.update(&front_matter);
+                literate.bare_fences = has_bare_fences(path);
+
+                let mut sequence = if ext == std::ffi::OsStr::new("rst") {
+                    let mut sequence = Self {
+                        literate,
+                        ..Default::default()
+                    };
+                    for block in extract_rst(&normalized) {
+                        let steps = Self::parse_trycmd_with(&block, &sequence.literate)?.steps;
+                        sequence.steps.extend(steps);
+                    }
+                    sequence
+                } else if ext == std::ffi::OsStr::new("adoc") {
+                    Self::parse_trycmd_with(&extract_adoc(&normalized), &literate)?
+                } else {
+                    Self::parse_trycmd_from(&normalized, &literate, &[path])?
+                };
+                front_matter.apply(&mut sequence);
+                sequence
             } else {
                 return Err(format!("Unsupported extension: {}", ext.to_string_lossy()).into());
             }
@@ -91,7 +136,7 @@ impl TryCmd {
         });
 
         if sequence.fs.base.is_none() {
-            let base_path = path.with_extension("in");
+            let base_path = layout.sibling(path, "in");
             if base_path.exists() {
                 sequence.fs.base = Some(base_path);
             } else if sequence.fs.cwd.is_some() {
@@ -102,7 +147,7 @@ impl TryCmd {
             sequence.fs.cwd.clone_from(&sequence.fs.base);
         }
         if sequence.fs.sandbox.is_none() {
-            sequence.fs.sandbox = Some(path.with_extension("out").exists());
+            sequence.fs.sandbox = Some(layout.sibling(path, "out").exists());
         }
 
         sequence.fs.base = sequence
@@ -118,23 +163,68 @@ impl TryCmd {
             .map(|p| snapbox::dir::resolve_dir(p).map_err(|e| e.to_string()))
             .transpose()?;
 
+        for capture in sequence.steps.iter().flat_map(|s| &s.captures) {
+            capture.validate()?;
+        }
+
         Ok(sequence)
     }
 
+    /// Read the `[matrix]` of a case
+    pub(crate) fn load_matrix(
+        path: &std::path::Path,
+    ) -> Result<BTreeMap<String, Vec<String>>, crate::Error> {
+        let ext = path
+            .extension()
+            .ok_or_else(|| crate::Error::new("No extension"))?;
+        let raw = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let matrix = if ext == std::ffi::OsStr::new("toml") {
+            OneShot::parse_toml(&raw)?.matrix
+        } else if is_literate(ext) {
+            let mut normalized = snapbox::filter::normalize_lines(&raw);
+            if ext == std::ffi::OsStr::new("rs") {
+                normalized = extract_doc_comments(&normalized);
+            }
+            FrontMatter::split(&normalized)?.0.matrix
+        } else {
+            return Err(format!("Unsupported extension: {}", ext.to_string_lossy()).into());
+        };
+        if let Some((name, _)) = matrix.iter().find(|(_, values)| values.is_empty()) {
+            return Err(format!("`matrix.{}` has no values", name).into());
+        }
+        Ok(matrix)
+    }
+
     pub(crate) fn overwrite(
         &self,
         path: &std::path::Path,
+        layout: Layout,
         id: Option<&str>,
-        stdout: Option<&crate::Data>,
-        stderr: Option<&crate::Data>,
-        exit: Option<std::process::ExitStatus>,
+        variant: Option<&str>,
+        actual: Actual<'_>,
     ) -> Result<(), crate::Error> {
+        let Actual {
+            stdout,
+            stderr,
+            exit,
+            files,
+            mocks,
+        } = actual;
         if let Some(ext) = path.extension() {
             if ext == std::ffi::OsStr::new("toml") {
                 assert_eq!(id, None);
 
-                overwrite_toml_output(path, id, stdout, "stdout", "stdout")?;
-                overwrite_toml_output(path, id, stderr, "stderr", "stderr")?;
+                overwrite_toml_output(path, layout, variant, stdout, "stdout", "stdout")?;
+                overwrite_toml_output(path, layout, variant, stderr, "stderr", "stderr")?;
+                if let Some(mocks) = mocks {
+                    // Unlike `stdout`, the `mocks` field is the definitions
+                    let ext = match variant {
+                        Some(variant) => format!("{}.mocks", variant),
+                        None => "mocks".to_owned(),
+                    };
+                    mocks.write_to_path(&layout.sibling(path, &ext))?;
+                }
 
                 if let Some(status) = exit {
                     let raw = std::fs::read_to_string(path)
@@ -144,7 +234,7 @@ impl TryCmd {
                     std::fs::write(path, overwritten)
                         .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
                 }
-            } else if ext == std::ffi::OsStr::new("trycmd") || ext == std::ffi::OsStr::new("md") {
+            } else if is_literate(ext) {
                 if stderr.is_some() && stderr != Some(&crate::Data::new()) {
                     panic!("stderr should have been merged: {:?}", stderr);
                 }
@@ -159,16 +249,41 @@ impl TryCmd {
                         .clone()
                         .expect("always present for .trycmd");
 
+                    // Included steps are overwritten in the file they came from
+                    let path = step.source.as_deref().unwrap_or(path);
                     let raw = std::fs::read_to_string(path)
                         .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
                     let mut normalized = snapbox::filter::normalize_lines(&raw);
+                    let cmd_start = id
+                        .rsplit(':')
+                        .next()
+                        .and_then(|line| line.parse::<usize>().ok())
+                        .expect("id ends with a line number");
+                    let prefix =
+                        cmd_prefix(&normalized, cmd_start, &self.literate.prompts).to_owned();
+
+                    let mut blocks = step
+                        .expected_files
+                        .iter()
+                        .filter_map(|block| Some((block.source.clone(), files.get(&block.path)?)))
+                        .chain(step.expected_mocks_source.clone().zip(mocks))
+                        .collect::<Vec<_>>();
+                    // Last first as these come after the status and stdout, and each other
+                    blocks.sort_by_key(|(source, _)| std::cmp::Reverse(source.start));
+                    for (source, actual) in blocks {
+                        let mut content = actual.render().expect("at least Text");
+                        if !content.is_empty() && !content.ends_with('\n') {
+                            content.push('\n');
+                        }
+                        replace_lines(&mut normalized, source, &prefix_lines(&content, &prefix))?;
+                    }
 
-                    overwrite_trycmd_status(exit, step, &mut line_nums, &mut normalized)?;
+                    overwrite_trycmd_status(exit, step, &mut line_nums, &mut normalized, &prefix)?;
 
                     let mut stdout = stdout.render().expect("at least Text");
                     // Add back trailing newline removed when parsing
                     stdout.push('\n');
-                    replace_lines(&mut normalized, line_nums, &stdout)?;
+                    replace_lines(&mut normalized, line_nums, &prefix_lines(&stdout, &prefix))?;
 
                     std::fs::write(path, normalized.into_bytes())
                         .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
@@ -184,7 +299,21 @@ impl TryCmd {
     }
 
     fn parse_trycmd(s: &str) -> Result<Self, crate::Error> {
-        let mut steps = Vec::new();
+        Self::parse_trycmd_with(s, &Literate::default())
+    }
+
+    fn parse_trycmd_with(s: &str, literate: &Literate) -> Result<Self, crate::Error> {
+        Self::parse_trycmd_from(s, literate, &[])
+    }
+
+    /// Parse `s`, read from the last of `paths`, splicing in `<!-- trycmd:include <path> -->`
+    fn parse_trycmd_from(
+        s: &str,
+        literate: &Literate,
+        paths: &[&std::path::Path],
+    ) -> Result<Self, crate::Error> {
+        let mut steps: Vec<Step> = Vec::new();
+        let mut files: Vec<FileBlock> = Vec::new();
 
         let mut lines: VecDeque<_> = snapbox::utils::LinesWithTerminator::new(s)
             .enumerate()
@@ -192,7 +321,19 @@ impl TryCmd {
             .collect();
         'outer: loop {
             let mut fence_pattern = "```".to_owned();
-            while let Some((_, line)) = lines.pop_front() {
+            let mut continue_on_error = false;
+            let mut always_run = false;
+            let mut block_start = 0;
+            while let Some((line_num, line)) = lines.pop_front() {
+                if let Some(include) = parse_include(line) {
+                    let included = load_include(include, line_num, literate, paths)?;
+                    steps.extend(included.into_iter().map(|mut step| {
+                        step.id = step.id.map(|id| format!("{}:{}", line_num, id));
+                        step.block = Some(line_num..line_num + 1);
+                        step
+                    }));
+                    continue;
+                }
                 let tick_end = line
                     .char_indices()
                     .find_map(|(i, c)| (c != '`').then_some(i))
@@ -201,22 +342,70 @@ impl TryCmd {
                     line[..tick_end].clone_into(&mut fence_pattern);
                     let raw = line[tick_end..].trim();
                     if raw.is_empty() {
-                        // Assuming a trycmd block
-                        break;
+                        if literate.bare_fences {
+                            // Assuming a trycmd block
+                            block_start = line_num;
+                            break;
+                        }
+                        snapbox::debug!("ignore bare fence on line {}", line_num);
                     } else {
                         let mut info = raw.split(',');
                         let lang = info.next().unwrap();
-                        match lang {
-                            "trycmd" | "console" => {
-                                if info.any(|i| i == "ignore") {
-                                    snapbox::debug!("ignore from infostring: {:?}", info);
-                                } else {
-                                    break;
-                                }
-                            }
-                            _ => {
-                                snapbox::debug!("ignore from lang: {:?}", lang);
+                        let attrs = info.map(|i| i.trim()).collect::<Vec<_>>();
+                        if attrs.contains(&"ignore") {
+                            // Fall through to consume it
+                        } else if let Some(path) =
+                            attrs.iter().find_map(|a| a.strip_prefix("file="))
+                        {
+                            files.push(FileBlock::parse(path, &mut lines, &fence_pattern));
+                            continue;
+                        } else if let Some(path) =
+                            attrs.iter().find_map(|a| a.strip_prefix("expect-file="))
+                        {
+                            let block = FileBlock::parse(path, &mut lines, &fence_pattern);
+                            // Blocks are overwritten in the step's file
+                            let step = steps
+                                .last_mut()
+                                .filter(|step| step.source.is_none())
+                                .ok_or_else(|| {
+                                    format!(
+                                        "`expect-file={}` on line {} must follow a command in the same file",
+                                        path, line_num
+                                    )
+                                })?;
+                            step.expected_files.push(block);
+                            continue;
+                        } else if attrs.contains(&"expect-mocks") {
+                            let block = FileBlock::parse("", &mut lines, &fence_pattern);
+                            let step = steps
+                                .last_mut()
+                                .filter(|step| {
+                                    step.source.is_none() && step.expected_mocks.is_none()
+                                })
+                                .ok_or_else(|| {
+                                    format!(
+                                        "`expect-mocks` on line {} must follow a command in the same file, once",
+                                        line_num
+                                    )
+                                })?;
+                            step.expected_mocks = Some(
+                                FilterNewlines
+                                    .filter(FilterPaths.filter(crate::Data::text(block.content))),
+                            );
+                            step.expected_mocks_source = Some(block.source);
+                            continue;
+                        }
+                        if literate.languages.iter().any(|l| l == lang) {
+                            if attrs.contains(&"ignore") {
+                                snapbox::debug!("ignore from infostring: {:?}", attrs);
+                            } else {
+                                continue_on_error = attrs.contains(&"continue-on-error");
+                                always_run = attrs.contains(&"always-run");
+                                block_start = line_num;
+                                break;
                             }
+                        } else {
+                            snapbox::debug!("ignore from lang: {:?}", lang);
                         }
                     }
 
@@ -229,56 +418,104 @@ impl TryCmd {
                 }
             }
 
+            let block_steps = steps.len();
+            let mut block_end = usize::MAX;
             'code: loop {
                 let mut cmdline = Vec::new();
+                let mut raw_cmdline = Vec::new();
                 let mut expected_status_source = None;
                 let mut expected_status = Some(CommandStatus::Success);
                 let mut stdout = String::new();
                 let cmd_start;
                 let mut stdout_start;
 
+                while lines
+                    .front()
+                    .map(|(_, line)| literate.is_comment(line))
+                    .unwrap_or(false)
+                {
+                    lines.pop_front();
+                }
                 if let Some((line_num, line)) = lines.pop_front() {
                     if line.starts_with(&fence_pattern) {
+                        block_end = line_num + 1;
                         break;
-                    } else if let Some(raw) = line.strip_prefix("$ ") {
+                    } else if let Some(raw) = literate.strip_prompt(line) {
                         cmdline.extend(shlex::Shlex::new(raw.trim()));
+                        raw_cmdline.push(raw.trim());
                         cmd_start = line_num;
                         stdout_start = line_num + 1;
                     } else {
-                        return Err(
-                            format!("Expected `$` on line {}, got `{}`", line_num, line).into()
-                        );
+                        let prompts = literate
+                            .prompts
+                            .iter()
+                            .map(|p| format!("`{}`", p.trim()))
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(format!(
+                            "Expected {} on line {}, got `{}`",
+                            prompts, line_num, line
+                        )
+                        .into());
                     }
                 } else {
-                    break 'outer;
+                    break;
                 }
                 while let Some((line_num, line)) = lines.pop_front() {
-                    if let Some(raw) = line.strip_prefix("> ") {
+                    if let Some(raw) = literate.strip_continuation(line) {
                         cmdline.extend(shlex::Shlex::new(raw.trim()));
+                        raw_cmdline.push(raw.trim());
                         stdout_start = line_num + 1;
                     } else {
                         lines.push_front((line_num, line));
                         break;
                     }
                 }
-                if let Some((line_num, line)) = lines.pop_front() {
-                    if let Some(raw) = line.strip_prefix("? ") {
+                let mut captures = Vec::new();
+                while let Some((line_num, line)) = lines.pop_front() {
+                    if let (Some(raw), None) = (line.strip_prefix("? "), expected_status_source) {
                         expected_status_source = Some(line_num);
                         expected_status = Some(raw.trim().parse::<CommandStatus>()?);
                         stdout_start = line_num + 1;
+                    } else if let Some(raw) = line.strip_prefix("=> ") {
+                        let (name, regex) = match raw.trim().split_once(' ') {
+                            Some((name, regex)) => (name, Some(regex.trim().to_owned())),
+                            None => (raw.trim(), None),
+                        };
+                        captures.push(Capture {
+                            name: name.to_owned(),
+                            regex,
+                        });
+                        stdout_start = line_num + 1;
                     } else {
                         lines.push_front((line_num, line));
+                        break;
                     }
                 }
                 let mut post_stdout_start = stdout_start;
                 let mut block_done = false;
                 while let Some((line_num, line)) = lines.pop_front() {
-                    if line.starts_with("$ ") {
+                    if literate.strip_prompt(line).is_some() {
+                        lines.push_front((line_num, line));
+                        post_stdout_start = line_num;
+                        break;
+                    } else if literate.is_comment(line)
+                        && lines
+                            .iter()
+                            .find(|(_, l)| !literate.is_comment(l))
+                            .map(|(_, l)| {
+                                literate.strip_prompt(l).is_some() || l.starts_with(&fence_pattern)
+                            })
+                            .unwrap_or(true)
+                    {
+                        // Only comments leading up to the next command (or the end of the block)
+                        // so output can still look like comments
                         lines.push_front((line_num, line));
                         post_stdout_start = line_num;
                         break;
                     } else if line.starts_with(&fence_pattern) {
                         block_done = true;
+                        block_end = line_num + 1;
                         post_stdout_start = line_num;
                         break;
                     } else {
@@ -294,22 +531,33 @@ impl TryCmd {
 
                 let mut env = Env::default();
 
-                let bin = loop {
-                    if cmdline.is_empty() {
-                        return Err(format!("No bin specified on line {}", cmd_start).into());
-                    }
-                    let next = cmdline.remove(0);
-                    if let Some((key, value)) = next.split_once('=') {
-                        env.add.insert(key.to_owned(), value.to_owned());
-                    } else {
-                        break next;
-                    }
+                let (bin, shell_cmdline) = if literate.shell_syntax {
+                    // Expanded when run
+                    let shell_cmdline = crate::shell::CommandLine::parse(&raw_cmdline.join(" "))
+                        .map_err(|e| format!("{} on line {}", e, cmd_start))?;
+                    cmdline.clear();
+                    (None, Some(shell_cmdline))
+                } else {
+                    let bin = loop {
+                        if cmdline.is_empty() {
+                            return Err(format!("No bin specified on line {}", cmd_start).into());
+                        }
+                        let next = cmdline.remove(0);
+                        if let Some((key, value)) = next.split_once('=') {
+                            env.add.insert(key.to_owned(), value.to_owned());
+                        } else {
+                            break next;
+                        }
+                    };
+                    (Some(Bin::Name(bin)), None)
                 };
                 let step = Step {
                     id: Some(cmd_start.to_string()),
-                    bin: Some(Bin::Name(bin)),
+                    source: None,
+                    bin,
                     args: cmdline,
                     env,
+                    shell_cmdline,
                     stdin: None,
                     stderr_to_stdout: true,
                     expected_status_source,
@@ -320,30 +568,260 @@ impl TryCmd {
                     expected_stderr: None,
                     binary: false,
                     timeout: None,
+                    captures,
+                    continue_on_error,
+                    always_run,
+                    files: std::mem::take(&mut files),
+                    expected_files: Vec::new(),
+                    expected_mocks_source: None,
+                    expected_mocks: None,
+                    block: None,
                 };
                 steps.push(step);
                 if block_done {
                     break 'code;
                 }
             }
+            for step in &mut steps[block_steps..] {
+                step.block = Some(block_start..block_end);
+            }
+            if lines.is_empty() {
+                break;
+            }
+        }
+
+        if let Some(file) = files.first() {
+            return Err(format!(
+                "`file={}` on line {} must be followed by a command",
+                file.path,
+                file.source.start - 1
+            )
+            .into());
         }
 
         Ok(Self {
             steps,
+            literate: literate.clone(),
             ..Default::default()
         })
     }
 }
 
+/// What a step produced, for [`TryCmd::overwrite`]
+pub(crate) struct Actual<'a> {
+    pub(crate) stdout: Option<&'a crate::Data>,
+    pub(crate) stderr: Option<&'a crate::Data>,
+    pub(crate) exit: Option<std::process::ExitStatus>,
+    /// Content of files with an `expect-file=<path>` block, by path
+    pub(crate) files: &'a BTreeMap<String, crate::Data>,
+    pub(crate) mocks: Option<&'a crate::Data>,
+}
+
+/// Contents of a fenced `file=<path>` or `expect-file=<path>` block
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub(crate) struct FileBlock {
+    /// Relative to the command's directory in the sandbox
+    pub(crate) path: String,
+    pub(crate) content: String,
+    /// Lines of `content`, for overwriting
+    pub(crate) source: std::ops::Range<usize>,
+}
+
+impl FileBlock {
+    /// Consume the rest of the block, up to the closing fence
+    fn parse(path: &str, lines: &mut VecDeque<(usize, &str)>, fence_pattern: &str) -> Self {
+        let start = lines.front().map(|(line_num, _)| *line_num).unwrap_or(0);
+        let mut end = start;
+        let mut content = String::new();
+        while let Some((line_num, line)) = lines.pop_front() {
+            if line.starts_with(fence_pattern) {
+                break;
+            }
+            content.push_str(line);
+            end = line_num + 1;
+        }
+        Self {
+            path: path.to_owned(),
+            content,
+            source: start..end,
+        }
+    }
+}
+
+/// Syntax for code blocks in `*.trycmd` and other literate files
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Literate {
+    pub(crate) languages: Vec<String>,
+    pub(crate) prompts: Vec<String>,
+    pub(crate) continuations: Vec<String>,
+    pub(crate) comments: Vec<String>,
+    pub(crate) shell_syntax: bool,
+    /// Whether a fence without a language is a code block to run
+    pub(crate) bare_fences: bool,
+}
+
+impl Literate {
+    pub(crate) fn update(&mut self, other: &FrontMatter) {
+        extend_unique(&mut self.languages, &other.languages);
+        extend_unique(&mut self.prompts, &other.prompts);
+        extend_unique(&mut self.continuations, &other.continuations);
+        extend_unique(&mut self.comments, &other.comments);
+        if let Some(shell_syntax) = other.shell_syntax {
+            self.shell_syntax = shell_syntax;
+        }
+    }
+
+    fn strip_prompt<'l>(&self, line: &'l str) -> Option<&'l str> {
+        self.prompts
+            .iter()
+            .find_map(|p| line.strip_prefix(p.as_str()))
+    }
+
+    fn strip_continuation<'l>(&self, line: &'l str) -> Option<&'l str> {
+        self.continuations
+            .iter()
+            .find_map(|p| line.strip_prefix(p.as_str()))
+    }
+
+    fn is_comment(&self, line: &str) -> bool {
+        self.comments.iter().any(|c| line.starts_with(c.as_str()))
+    }
+}
+
+impl Default for Literate {
+    fn default() -> Self {
+        Self {
+            languages: vec!["trycmd".into(), "console".into()],
+            prompts: vec!["$ ".into()],
+            continuations: vec!["> ".into()],
+            comments: vec![],
+            shell_syntax: false,
+            bare_fences: true,
+        }
+    }
+}
+
+pub(crate) fn extend_unique(items: &mut Vec<String>, other: &[String]) {
+    for item in other {
+        if !items.contains(item) {
+            items.push(item.clone());
+        }
+    }
+}
+
+/// Settings at the top of literate files, between `+++` lines
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct FrontMatter {
+    /// Additional code block languages to run
+    #[serde(default)]
+    pub(crate) languages: Vec<String>,
+    /// Additional line prefixes that start a command
+    #[serde(default)]
+    pub(crate) prompts: Vec<String>,
+    /// Additional line prefixes that continue a command
+    #[serde(default)]
+    pub(crate) continuations: Vec<String>,
+    /// Line prefixes for comments before a command
+    #[serde(default)]
+    pub(crate) comments: Vec<String>,
+    /// Expand variables and `$(...)` in commands
+    #[serde(default)]
+    pub(crate) shell_syntax: Option<bool>,
+    /// Run the case once for each combination of values, available as `{{name}}`
+    #[serde(default)]
+    pub(crate) matrix: BTreeMap<String, Vec<String>>,
+    /// Program for commands that don't name one
+    pub(crate) bin: Option<Bin>,
+    /// Environment for every command, overridden by `KEY=VALUE` on a command
+    #[serde(default)]
+    pub(crate) env: Env,
+    #[serde(default)]
+    pub(crate) fs: Filesystem,
+    #[serde(default)]
+    #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[serde(serialize_with = "humantime_serde::serialize")]
+    pub(crate) timeout: Option<std::time::Duration>,
+    /// Fake commands to put first on `PATH`
+    #[serde(default)]
+    pub(crate) mocks: BTreeMap<String, Mock>,
+}
+
+impl FrontMatter {
+    /// Apply the settings for the whole file to its steps
+    fn apply(&self, sequence: &mut TryCmd) {
+        sequence.fs = self.fs.clone();
+        sequence.mocks = self.mocks.clone();
+        for step in &mut sequence.steps {
+            if step.bin.is_none() {
+                step.bin.clone_from(&self.bin);
+            }
+            let mut env = self.env.clone();
+            env.update(&step.env);
+            step.env = env;
+            if step.timeout.is_none() {
+                step.timeout = self.timeout;
+            }
+        }
+    }
+
+    /// Render as the start of a literate file, empty when there are no settings
+    pub(crate) fn to_trycmd(&self) -> Result<String, crate::Error> {
+        if *self == Self::default() {
+            return Ok(String::new());
+        }
+        let mut doc = toml_edit::ser::to_document(self).map_err(|e| e.to_string())?;
+        doc.retain(|_, item| item.as_value_mut().map(is_nonempty).unwrap_or(true));
+        // Match the style of hand-written front matter, like `env.hermetic = true`
+        for (_, item) in doc.iter_mut() {
+            if let Some(table) = item.as_inline_table_mut() {
+                table.set_dotted(true);
+            }
+        }
+        Ok(format!("+++\n{}+++\n\n", doc))
+    }
+
+    /// Parse the front matter, blanking it out to preserve line numbers
+    fn split(s: &str) -> Result<(Self, String), crate::Error> {
+        const DELIMITER: &str = "+++";
+
+        let mut lines = snapbox::utils::LinesWithTerminator::new(s);
+        match lines.next() {
+            Some(line) if line.trim_end() == DELIMITER => {}
+            _ => return Ok((Self::default(), s.to_owned())),
+        }
+        let mut raw = String::new();
+        let mut blanked = String::from("\n");
+        for line in lines.by_ref() {
+            blanked.push('\n');
+            if line.trim_end() == DELIMITER {
+                let front_matter = toml_edit::de::from_str(&raw)
+                    .map_err(|e| format!("Invalid front matter: {}", e))?;
+                blanked.extend(lines);
+                return Ok((front_matter, blanked));
+            }
+            raw.push_str(line);
+        }
+        Err(format!("Front matter is missing the closing `{}`", DELIMITER).into())
+    }
+}
+
 fn overwrite_toml_output(
     path: &std::path::Path,
-    _id: Option<&str>,
+    layout: Layout,
+    variant: Option<&str>,
     output: Option<&crate::Data>,
     output_ext: &str,
     output_field: &str,
 ) -> Result<(), crate::Error> {
     if let Some(output) = output {
-        let output_path = path.with_extension(output_ext);
+        if let Some(variant) = variant {
+            // Other combinations may still match the shared output
+            let output_path = layout.sibling(path, &format!("{}.{}", variant, output_ext));
+            return output.write_to_path(&output_path);
+        }
+
+        let output_path = layout.sibling(path, output_ext);
         if output_path.exists() {
             output.write_to_path(&output_path)?;
         } else if let Some(output) = output.render() {
@@ -446,6 +924,7 @@ fn overwrite_trycmd_status(
     step: &Step,
     stdout_line_nums: &mut std::ops::Range<usize>,
     normalized: &mut String,
+    prefix: &str,
 ) -> Result<(), crate::Error> {
     let status = match exit {
         Some(status) => status,
@@ -484,6 +963,7 @@ fn overwrite_trycmd_status(
     };
 
     if let Some(status) = formatted_status {
+        let status = format!("{prefix}{status}");
         if let Some(line_num) = step.expected_status_source {
             replace_lines(normalized, line_num..(line_num + 1), &status)?;
         } else {
@@ -496,6 +976,263 @@ fn overwrite_trycmd_status(
     Ok(())
 }
 
+/// Names of the case file within a case directory, like `tests/cmd/basic/cmd.toml`
+const CASE_FILES: &[&str] = &["cmd.toml", "case.trycmd"];
+
+/// The case file of `dir`, if it is a case directory
+pub(crate) fn case_file(dir: &std::path::Path) -> Option<std::path::PathBuf> {
+    CASE_FILES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// How a case's files are arranged
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+pub(crate) enum Layout {
+    /// Named after the case file, like `basic.toml` and `basic.stdout`
+    #[default]
+    Files,
+    /// Within a directory found as a case, like `basic/cmd.toml` and `basic/stdout`
+    Dir,
+}
+
+impl Layout {
+    /// The `ext` file or directory of the case at `path`, like `basic.stdout` or `basic/stdout`
+    pub(crate) fn sibling(self, path: &std::path::Path, ext: &str) -> std::path::PathBuf {
+        match self {
+            Self::Files => path.with_extension(ext),
+            Self::Dir => path.with_file_name(ext),
+        }
+    }
+}
+
+/// Files with code blocks that are parsed like `*.trycmd`
+fn is_literate(ext: &std::ffi::OsStr) -> bool {
+    ["trycmd", "md", "rs", "rst", "adoc"]
+        .iter()
+        .any(|e| ext == std::ffi::OsStr::new(e))
+}
+
+/// The path in a `<!-- trycmd:include <path> -->` line
+fn parse_include(line: &str) -> Option<&str> {
+    let include = line
+        .trim()
+        .strip_prefix("<!--")?
+        .strip_suffix("-->")?
+        .trim()
+        .strip_prefix("trycmd:include")?;
+    include
+        .starts_with(char::is_whitespace)
+        .then(|| include.trim())
+}
+
+/// Steps from an included file, resolved relative to the file including it
+fn load_include(
+    include: &str,
+    line_num: usize,
+    literate: &Literate,
+    paths: &[&std::path::Path],
+) -> Result<Vec<Step>, crate::Error> {
+    let parent = paths
+        .last()
+        .ok_or_else(|| {
+            format!(
+                "`trycmd:include` is only supported in files on line {}",
+                line_num
+            )
+        })?
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."));
+    let path = parent.join(include);
+    if paths.iter().any(|p| same_file(p, &path).unwrap_or(false)) {
+        return Err(format!("`{}` includes itself on line {}", include, line_num).into());
+    }
+    let raw = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let normalized = snapbox::filter::normalize_lines(&raw);
+    // Settings come from the file being run
+    let (_, normalized) = FrontMatter::split(&normalized)?;
+    let mut literate = literate.clone();
+    literate.bare_fences = has_bare_fences(&path);
+    let mut paths = paths.to_vec();
+    paths.push(&path);
+    let steps = TryCmd::parse_trycmd_from(&normalized, &literate, &paths)?
+        .steps
+        .into_iter()
+        .map(|mut step| {
+            step.source.get_or_insert_with(|| path.clone());
+            step
+        })
+        .collect();
+    Ok(steps)
+}
+
+/// In Rust doc comments, a fence without a language is a doctest
+fn has_bare_fences(path: &std::path::Path) -> bool {
+    path.extension() != Some(std::ffi::OsStr::new("rs"))
+}
+
+fn same_file(a: &std::path::Path, b: &std::path::Path) -> std::io::Result<bool> {
+    Ok(a.canonicalize()? == b.canonicalize()?)
+}
+
+/// Blank out everything but the contents of `///` and `//!` comments, preserving line numbers
+fn extract_doc_comments(s: &str) -> String {
+    let mut extracted = String::new();
+    for line in snapbox::utils::LinesWithTerminator::new(s) {
+        let trimmed = line.trim_start();
+        let content = trimmed
+            .strip_prefix("///")
+            // `////` is a regular comment
+            .filter(|c| !c.starts_with('/'))
+            .or_else(|| trimmed.strip_prefix("//!"));
+        match content {
+            Some(content) => extracted.push_str(content.strip_prefix(' ').unwrap_or(content)),
+            None => extracted.push('\n'),
+        }
+    }
+    extracted
+}
+
+/// Extract `.. code-block:: <lang>` directives as fenced code blocks, preserving line numbers
+///
+/// Each block is left unterminated so the first blank line after it is part of the content, like
+/// the trailing blank line in a fenced code block.
+fn extract_rst(s: &str) -> Vec<String> {
+    let lines: Vec<_> = snapbox::utils::LinesWithTerminator::new(s).collect();
+    let mut blocks = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let trimmed = lines[i].trim();
+        let lang = ["code-block", "code", "sourcecode"]
+            .iter()
+            .find_map(|d| {
+                trimmed
+                    .strip_prefix(".. ")?
+                    .strip_prefix(d)?
+                    .strip_prefix("::")
+            })
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty());
+        i += 1;
+        let lang = match lang {
+            Some(lang) => lang,
+            None => continue,
+        };
+
+        // Directive options, like `:caption:`
+        while i < lines.len() && lines[i].trim_start().starts_with(':') {
+            i += 1;
+        }
+        let mut block = String::new();
+        let mut indent = None;
+        let mut blank = 0;
+        while i < lines.len() {
+            let line = lines[i];
+            if line.trim().is_empty() {
+                blank += 1;
+                i += 1;
+                continue;
+            }
+            let line_indent = line.len() - line.trim_start().len();
+            if indent.is_none() && 0 < line_indent {
+                // Open the fence on the line before the content
+                block = "\n".repeat(i - 1);
+                block.push_str(&format!("```{lang}\n"));
+                blank = 0;
+            }
+            let indent = *indent.get_or_insert(line_indent);
+            if line_indent == 0 || line_indent < indent {
+                break;
+            }
+            block.push_str(&"\n".repeat(blank));
+            blank = 0;
+            block.push_str(&line[indent..]);
+            i += 1;
+        }
+        if indent.is_some() && 0 < blank {
+            block.push('\n');
+        }
+        if !block.is_empty() {
+            blocks.push(block);
+        }
+    }
+    blocks
+}
+
+/// Rewrite `[source,<lang>]` listing blocks as fenced code blocks, preserving line numbers
+fn extract_adoc(s: &str) -> String {
+    let mut extracted = String::new();
+    let mut lang = None;
+    // The current listing's delimiter and whether to keep its content
+    let mut listing: Option<(&str, bool)> = None;
+    for line in snapbox::utils::LinesWithTerminator::new(s) {
+        let trimmed = line.trim_end();
+        if let Some((delimiter, keep)) = listing {
+            if trimmed == delimiter {
+                listing = None;
+                extracted.push_str(if keep { "```\n" } else { "\n" });
+            } else if keep {
+                extracted.push_str(line);
+            } else {
+                extracted.push('\n');
+            }
+        } else if 4 <= trimmed.len() && trimmed.chars().all(|c| c == '-') {
+            match lang.take() {
+                Some(lang) => {
+                    extracted.push_str(&format!("```{lang}\n"));
+                    listing = Some((trimmed, true));
+                }
+                None => {
+                    extracted.push('\n');
+                    listing = Some((trimmed, false));
+                }
+            }
+        } else if let Some(attrs) = trimmed.strip_prefix('[').and_then(|a| a.strip_suffix(']')) {
+            let mut attrs = attrs.split(',').map(|a| a.trim());
+            let style = attrs.next().unwrap_or_default();
+            let style = style.split(['%', '#', '.']).next().unwrap_or_default();
+            if style == "source" || style.is_empty() {
+                lang = attrs.next().filter(|l| !l.is_empty() && !l.contains('='));
+            }
+            extracted.push('\n');
+        } else {
+            // Block titles can go between the attributes and the listing
+            if !trimmed.starts_with('.') {
+                lang = None;
+            }
+            extracted.push('\n');
+        }
+    }
+    extracted
+}
+
+/// The indentation or comment leader, like `    /// `, for the command on `line_num`
+fn cmd_prefix<'s>(s: &'s str, line_num: usize, prompts: &[String]) -> &'s str {
+    let line = s.lines().nth(line_num - 1).unwrap_or_default();
+    let end = prompts
+        .iter()
+        .filter_map(|p| line.find(p.as_str()))
+        .min()
+        .unwrap_or_default();
+    &line[..end]
+}
+
+fn prefix_lines(text: &str, prefix: &str) -> String {
+    let mut prefixed = String::new();
+    for line in snapbox::utils::LinesWithTerminator::new(text) {
+        if line.trim_end_matches(['\r', '\n']).is_empty() {
+            // Avoid trailing whitespace
+            prefixed.push_str(prefix.trim_end());
+        } else {
+            prefixed.push_str(prefix);
+        }
+        prefixed.push_str(line);
+    }
+    prefixed
+}
+
 /// Update an inline snapshot
 fn replace_lines(
     data: &mut String,
@@ -544,14 +1281,19 @@ impl From<OneShot> for TryCmd {
             status,
             binary,
             timeout,
+            capture,
+            matrix: _,
+            mocks,
             fs,
         } = other;
         Self {
             steps: vec![Step {
                 id: None,
+                source: None,
                 bin,
                 args: args.into_vec(),
                 env,
+                shell_cmdline: None,
                 stdin: stdin.map(crate::Data::text),
                 stderr_to_stdout,
                 expected_status_source: None,
@@ -562,8 +1304,18 @@ impl From<OneShot> for TryCmd {
                 expected_stderr: stderr.map(crate::Data::text),
                 binary,
                 timeout,
+                captures: capture,
+                continue_on_error: false,
+                always_run: false,
+                files: Vec::new(),
+                expected_files: Vec::new(),
+                expected_mocks_source: None,
+                expected_mocks: None,
+                block: None,
             }],
             fs,
+            literate: Default::default(),
+            mocks,
         }
     }
 }
@@ -571,9 +1323,13 @@ impl From<OneShot> for TryCmd {
 #[derive(Clone, Default, Debug, PartialEq, Eq)]
 pub(crate) struct Step {
     pub(crate) id: Option<String>,
+    /// File the step was included from, when not the case itself
+    pub(crate) source: Option<std::path::PathBuf>,
     pub(crate) bin: Option<Bin>,
     pub(crate) args: Vec<String>,
     pub(crate) env: Env,
+    /// Replaces `bin`, `args`, and `env` when run
+    pub(crate) shell_cmdline: Option<crate::shell::CommandLine>,
     pub(crate) stdin: Option<crate::Data>,
     pub(crate) stderr_to_stdout: bool,
     pub(crate) expected_status_source: Option<usize>,
@@ -584,13 +1340,29 @@ pub(crate) struct Step {
     pub(crate) expected_stderr: Option<crate::Data>,
     pub(crate) binary: bool,
     pub(crate) timeout: Option<std::time::Duration>,
+    pub(crate) captures: Vec<Capture>,
+    /// Keep running later steps if this one fails
+    pub(crate) continue_on_error: bool,
+    /// Run even if an earlier step failed
+    pub(crate) always_run: bool,
+    /// Written into the sandbox before running
+    pub(crate) files: Vec<FileBlock>,
+    /// Verified in the sandbox after running
+    pub(crate) expected_files: Vec<FileBlock>,
+    /// Lines of the `expect-mocks` block
+    pub(crate) expected_mocks_source: Option<std::ops::Range<usize>>,
+    /// Calls to `mocks`, one per line
+    pub(crate) expected_mocks: Option<crate::Data>,
+    /// Lines of the code block, including its fences, or of the `trycmd:include`
+    pub(crate) block: Option<std::ops::Range<usize>>,
 }
 
 impl Step {
-    pub(crate) fn to_command(
+    /// Build the process for `bin`, `args`, and `env`, leaving the streams to the caller
+    pub(crate) fn to_std_command(
         &self,
         cwd: Option<&std::path::Path>,
-    ) -> Result<snapbox::cmd::Command, crate::Error> {
+    ) -> Result<std::process::Command, crate::Error> {
         let bin = match &self.bin {
             Some(Bin::Path(path)) => Ok(path.clone()),
             Some(Bin::Name(name)) => Err(format!("Unknown bin.name = {}", name).into()),
@@ -602,10 +1374,21 @@ impl Step {
             return Err(format!("Bin doesn't exist: {}", bin.display()).into());
         }
 
-        let mut cmd = snapbox::cmd::Command::new(bin).args(&self.args);
+        let mut cmd = std::process::Command::new(bin);
+        cmd.args(&self.args);
         if let Some(cwd) = cwd {
-            cmd = cmd.current_dir(cwd);
+            cmd.current_dir(cwd);
         }
+        self.env.apply(&mut cmd);
+
+        Ok(cmd)
+    }
+
+    pub(crate) fn to_command(
+        &self,
+        cwd: Option<&std::path::Path>,
+    ) -> Result<snapbox::cmd::Command, crate::Error> {
+        let mut cmd = snapbox::cmd::Command::from_std(self.to_std_command(cwd)?);
         if let Some(stdin) = &self.stdin {
             cmd = cmd.stdin(stdin);
         }
@@ -615,7 +1398,6 @@ impl Step {
         if let Some(timeout) = self.timeout {
             cmd = cmd.timeout(timeout);
         }
-        cmd = self.env.apply(cmd);
 
         Ok(cmd)
     }
@@ -623,6 +1405,123 @@ impl Step {
     pub(crate) fn expected_status(&self) -> CommandStatus {
         self.expected_status.unwrap_or_default()
     }
+
+    /// Render the environment, bin, and args as `/bin/sh` words for what [`Step::to_command`] runs
+    pub(crate) fn to_shell_words(&self) -> Vec<String> {
+        let quote = crate::shell::quote;
+        let mut words = Vec::new();
+        let env = &self.env;
+        let clear = env.hermetic() || !env.inherit();
+        if clear || !env.remove.is_empty() || !env.add.is_empty() {
+            words.push("env".to_owned());
+            if clear {
+                words.push("-i".to_owned());
+                if env.hermetic() {
+                    let inherited = HERMETIC_INHERIT
+                        .iter()
+                        .filter_map(|name| std::env::var(name).ok().map(|value| (*name, value)));
+                    let values = HERMETIC_VALUES
+                        .iter()
+                        .map(|(name, value)| (*name, (*value).to_owned()));
+                    for (name, value) in inherited.chain(values) {
+                        if !env.remove.iter().any(|r| r == name) && !env.add.contains_key(name) {
+                            words.push(format!("{}={}", name, quote(&value)));
+                        }
+                    }
+                }
+            } else {
+                for name in &env.remove {
+                    words.push("-u".to_owned());
+                    words.push(quote(name));
+                }
+            }
+            for (name, value) in &env.add {
+                words.push(format!("{}={}", name, quote(value)));
+            }
+        }
+        match &self.bin {
+            Some(Bin::Path(path)) => words.push(quote(&path.to_string_lossy())),
+            Some(Bin::Name(name)) => words.push(quote(name)),
+            Some(Bin::Ignore) | Some(Bin::Error(_)) | None => {}
+        }
+        words.extend(self.args.iter().map(|arg| quote(arg)));
+        words
+    }
+
+    /// Render as a `*.trycmd` code block
+    pub(crate) fn to_trycmd(&self) -> Result<String, crate::Error> {
+        let bin = match &self.bin {
+            Some(Bin::Name(name)) => name,
+            _ => return Err("Only `bin.name` can be represented in `*.trycmd`".into()),
+        };
+        let quote = |s: &str| {
+            shlex::try_quote(s)
+                .map(|s| s.into_owned())
+                .map_err(|e| crate::Error::new(e.to_string()))
+        };
+        let mut cmdline = Vec::new();
+        for (key, value) in &self.env.add {
+            cmdline.push(format!("{}={}", key, quote(value)?));
+        }
+        cmdline.push(quote(bin)?);
+        for arg in &self.args {
+            cmdline.push(quote(arg)?);
+        }
+
+        let status = match self.expected_status() {
+            CommandStatus::Success => None,
+            CommandStatus::Failed => Some("failed".to_owned()),
+            CommandStatus::Interrupted => Some("interrupted".to_owned()),
+            CommandStatus::Skipped => Some("skipped".to_owned()),
+            CommandStatus::Code(code) => Some(code.to_string()),
+        };
+
+        let stdout = match &self.expected_stdout {
+            Some(stdout) => stdout.render().ok_or_else(|| {
+                crate::Error::new("Binary data can't be represented in `*.trycmd`")
+            })?,
+            // Unverified
+            None => "...".to_owned(),
+        };
+        if let Some(line) = stdout.lines().find(|l| l.starts_with("$ ")) {
+            return Err(format!("Output can't be represented in `*.trycmd`: `{}`", line).into());
+        }
+
+        // Ensure the fence can't be closed by the output
+        let ticks = stdout
+            .lines()
+            .map(|l| l.chars().take_while(|c| *c == '`').count() + 1)
+            .max()
+            .unwrap_or_default()
+            .max(3);
+        let fence = "`".repeat(ticks);
+
+        let mut info = "console".to_owned();
+        if self.continue_on_error {
+            info.push_str(",continue-on-error");
+        }
+        if self.always_run {
+            info.push_str(",always-run");
+        }
+        let mut block = format!("{fence}{info}\n$ {}\n", cmdline.join(" "));
+        if let Some(status) = status {
+            block.push_str(&format!("? {status}\n"));
+        }
+        for capture in &self.captures {
+            match &capture.regex {
+                Some(regex) => block.push_str(&format!("=> {} {}\n", capture.name, regex)),
+                None => block.push_str(&format!("=> {}\n", capture.name)),
+            }
+        }
+        if !stdout.is_empty() {
+            block.push_str(&stdout);
+            // Add back trailing newline removed when parsing
+            block.push('\n');
+        }
+        block.push_str(&fence);
+        block.push('\n');
+        Ok(block)
+    }
 }
 
 /// Top-level data in `cmd.toml` files
@@ -648,7 +1547,17 @@ pub struct OneShot {
     pub(crate) binary: bool,
     #[serde(default)]
     #[serde(deserialize_with = "humantime_serde::deserialize")]
+    #[serde(serialize_with = "humantime_serde::serialize")]
     pub(crate) timeout: Option<std::time::Duration>,
+    /// Save `stdout` for later use as `[NAME]`, redacting it from output
+    #[serde(default)]
+    pub(crate) capture: Vec<Capture>,
+    /// Run the case once for each combination of values, available as `{{name}}`
+    #[serde(default)]
+    pub(crate) matrix: BTreeMap<String, Vec<String>>,
+    /// Fake commands to put first on `PATH`, recording their calls in `*.mocks`
+    #[serde(default)]
+    pub(crate) mocks: BTreeMap<String, Mock>,
     #[serde(default)]
     pub(crate) fs: Filesystem,
 }
@@ -657,6 +1566,60 @@ impl OneShot {
     fn parse_toml(s: &str) -> Result<Self, crate::Error> {
         toml_edit::de::from_str(s).map_err(|e| e.to_string().into())
     }
+
+    pub(crate) fn to_toml(&self) -> Result<String, crate::Error> {
+        let mut doc = toml_edit::ser::to_document(self).map_err(|e| e.to_string())?;
+        doc.retain(|key, item| match item.as_value_mut() {
+            // Only optional settings, where `false` still matters
+            Some(value) if key == "env" || key == "fs" => is_nonempty(value),
+            Some(value) => is_set(value),
+            None => true,
+        });
+        // Match the style of hand-written cases, like `bin.name = "cmd"`
+        for (key, item) in doc.iter_mut() {
+            if let Some(table) = item.as_inline_table_mut() {
+                if key == "env" {
+                    let mut table = std::mem::take(table).into_table();
+                    table.set_implicit(true);
+                    for (_, sub) in table.iter_mut() {
+                        if let Some(sub_table) = sub.as_inline_table_mut() {
+                            *sub = toml_edit::Item::Table(std::mem::take(sub_table).into_table());
+                        }
+                    }
+                    *item = toml_edit::Item::Table(table);
+                } else {
+                    table.set_dotted(true);
+                }
+            }
+        }
+        Ok(doc.to_string())
+    }
+}
+
+/// Whether `value` differs from the `#[serde(default)]`
+fn is_set(value: &mut toml_edit::Value) -> bool {
+    match value {
+        toml_edit::Value::Boolean(b) => *b.value(),
+        toml_edit::Value::String(s) => !s.value().is_empty(),
+        toml_edit::Value::Array(a) => !a.is_empty(),
+        toml_edit::Value::InlineTable(t) => {
+            t.retain(|_, v| is_set(v));
+            !t.is_empty()
+        }
+        _ => true,
+    }
+}
+
+/// Whether `value` has content, unlike [`is_set`] keeping `false`
+fn is_nonempty(value: &mut toml_edit::Value) -> bool {
+    match value {
+        toml_edit::Value::Array(a) => !a.is_empty(),
+        toml_edit::Value::InlineTable(t) => {
+            t.retain(|_, v| is_nonempty(v));
+            !t.is_empty()
+        }
+        _ => true,
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
@@ -708,7 +1671,6 @@ pub(crate) struct JoinedArgs {
 }
 
 impl JoinedArgs {
-    #[cfg(test)]
     pub(crate) fn from_vec(inner: Vec<String>) -> Self {
         JoinedArgs { inner }
     }
@@ -785,6 +1747,123 @@ impl Filesystem {
     }
 }
 
+/// Fake command on `PATH`, recording how it was called
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Mock {
+    /// Response when none of `cases` match
+    #[serde(flatten)]
+    pub(crate) response: MockResponse,
+    /// Responses for specific arguments, using the first that matches
+    #[serde(default)]
+    pub(crate) cases: Vec<MockCase>,
+}
+
+/// Response of a [`Mock`] for specific arguments
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MockCase {
+    /// Shell `case` pattern for the arguments, quoted when needed and joined with spaces, like
+    /// `clone 'my repo' *`
+    pub(crate) args: String,
+    #[serde(flatten)]
+    pub(crate) response: MockResponse,
+}
+
+/// Output of a [`Mock`]
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MockResponse {
+    #[serde(default)]
+    pub(crate) stdout: Option<String>,
+    #[serde(default)]
+    pub(crate) stderr: Option<String>,
+    /// Exit code, defaulting to `0`
+    #[serde(default)]
+    pub(crate) code: Option<i32>,
+}
+
+/// Placeholders the harness provides, like `[CWD]`
+const RESERVED_CAPTURES: &[&str] = &["CWD", "ROOT", "EXE", "HOME"];
+
+/// Save a command's output for `args`, `env`, and `stdin` of later commands to use as `[NAME]`
+#[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Capture {
+    /// Variable name, using `A-Z` and `_`
+    pub(crate) name: String,
+    /// Only save the capture group named `NAME` or, if there is none, the whole match
+    #[serde(default)]
+    pub(crate) regex: Option<String>,
+}
+
+impl Capture {
+    /// Placeholder for the value, like `[NAME]`
+    ///
+    /// Redactions need `'static` placeholders, so each is leaked once per process
+    pub(crate) fn placeholder(&self) -> Result<&'static str, crate::Error> {
+        static INTERNED: std::sync::Mutex<Vec<&'static str>> = std::sync::Mutex::new(Vec::new());
+
+        self.validate()?;
+        let placeholder = format!("[{}]", self.name);
+        let mut interned = INTERNED.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(existing) = interned.iter().find(|p| **p == placeholder) {
+            return Ok(existing);
+        }
+        let placeholder: &'static str = Box::leak(placeholder.into_boxed_str());
+        interned.push(placeholder);
+        Ok(placeholder)
+    }
+
+    fn validate(&self) -> Result<(), crate::Error> {
+        if self.name.is_empty()
+            || !self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c == '_')
+        {
+            return Err(format!(
+                "Invalid capture name `{}`, expected only `A-Z` and `_`",
+                self.name
+            )
+            .into());
+        }
+        if RESERVED_CAPTURES.contains(&self.name.as_str()) {
+            return Err(format!("Capture name `{}` is reserved", self.name).into());
+        }
+        Ok(())
+    }
+
+    /// Extract the value from `stdout`, without trailing newlines
+    pub(crate) fn extract(&self, stdout: &[u8]) -> Result<String, crate::Error> {
+        let stdout = std::str::from_utf8(stdout)
+            .map_err(|_| format!("Failed to capture `{}`: output is not UTF-8", self.name))?;
+        let value = match &self.regex {
+            Some(regex) => {
+                let regex = regex::Regex::new(regex)
+                    .map_err(|e| format!("Invalid regex for `{}`: {}", self.name, e))?;
+                let captures = regex.captures(stdout).ok_or_else(|| {
+                    format!(
+                        "Failed to capture `{}`: `{}` didn't match",
+                        self.name, regex
+                    )
+                })?;
+                captures
+                    .name(&self.name)
+                    .or_else(|| captures.get(0))
+                    .expect("the whole match always exists")
+                    .as_str()
+            }
+            None => stdout,
+        };
+        Ok(value.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
 /// Describe command's environment
 #[derive(Clone, Default, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -792,6 +1871,9 @@ impl Filesystem {
 pub struct Env {
     #[serde(default)]
     pub(crate) inherit: Option<bool>,
+    /// Start from deterministic values, like `LC_ALL=C` and `TZ=UTC`, instead of the host's
+    #[serde(default)]
+    pub(crate) hermetic: Option<bool>,
     #[serde(default)]
     pub(crate) add: BTreeMap<String, String>,
     #[serde(default)]
@@ -803,26 +1885,93 @@ impl Env {
         if self.inherit.is_none() {
             self.inherit = other.inherit;
         }
+        if self.hermetic.is_none() {
+            self.hermetic = other.hermetic;
+        }
         self.add
             .extend(other.add.iter().map(|(k, v)| (k.clone(), v.clone())));
         self.remove.extend(other.remove.iter().cloned());
     }
 
-    pub(crate) fn apply(&self, mut command: snapbox::cmd::Command) -> snapbox::cmd::Command {
-        if !self.inherit() {
-            command = command.env_clear();
+    pub(crate) fn apply(&self, command: &mut std::process::Command) {
+        if self.hermetic() {
+            command.env_clear();
+            for name in HERMETIC_INHERIT {
+                if let Some(value) = std::env::var_os(name) {
+                    command.env(name, value);
+                }
+            }
+            command.envs(HERMETIC_VALUES.iter().copied());
+        } else if !self.inherit() {
+            command.env_clear();
         }
         for remove in &self.remove {
-            command = command.env_remove(remove);
+            command.env_remove(remove);
         }
-        command.envs(&self.add)
+        command.envs(&self.add);
     }
 
     pub(crate) fn inherit(&self) -> bool {
         self.inherit.unwrap_or(true)
     }
+
+    pub(crate) fn hermetic(&self) -> bool {
+        self.hermetic.unwrap_or(false)
+    }
+
+    /// The value a command will see for `name`
+    pub(crate) fn get(&self, name: &str) -> Option<String> {
+        if let Some(value) = self.add.get(name) {
+            return Some(value.clone());
+        }
+        if self.remove.iter().any(|r| r == name) {
+            return None;
+        }
+        if self.hermetic() {
+            if let Some((_, value)) = HERMETIC_VALUES.iter().find(|(n, _)| *n == name) {
+                return Some((*value).to_owned());
+            }
+            if !HERMETIC_INHERIT.contains(&name) {
+                return None;
+            }
+        } else if !self.inherit() {
+            return None;
+        }
+        std::env::var(name).ok()
+    }
 }
 
+/// Host variables kept by [`Env::hermetic`], for finding and running programs
+const HERMETIC_INHERIT: &[&str] = &[
+    "PATH",
+    "PATHEXT",
+    "SYSTEMROOT",
+    "SYSTEMDRIVE",
+    "COMSPEC",
+    "TEMP",
+    "TMP",
+    "TMPDIR",
+];
+
+/// Host variables replaced by [`Env::hermetic`]
+const HERMETIC_VALUES: &[(&str, &str)] = &[
+    ("LANG", "C"),
+    ("LC_ALL", "C"),
+    ("TZ", "UTC"),
+    ("TERM", "dumb"),
+    ("SOURCE_DATE_EPOCH", "0"),
+];
+
+/// Directories under `HOME` for [`Env::hermetic`]
+pub(crate) const HERMETIC_HOME: &[(&str, &str)] = &[
+    ("HOME", ""),
+    ("USERPROFILE", ""),
+    ("XDG_CONFIG_HOME", ".config"),
+    ("XDG_CACHE_HOME", ".cache"),
+    ("XDG_DATA_HOME", ".local/share"),
+    ("XDG_STATE_HOME", ".local/state"),
+];
+
 /// Target under test
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 #[serde(rename_all = "kebab-case")]
@@ -935,6 +2084,7 @@ mod test {
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..5),
                 bin: Some(Bin::Name("cmd".into())),
                 expected_status: Some(CommandStatus::Success),
                 stderr_to_stdout: true,
@@ -961,6 +2111,7 @@ $ cmd
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..5),
                 bin: Some(Bin::Name("cmd".into())),
                 args: vec!["arg1".into(), "arg with space".into()],
                 expected_status: Some(CommandStatus::Success),
@@ -988,6 +2139,7 @@ $ cmd arg1 'arg with space'
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..6),
                 bin: Some(Bin::Name("cmd".into())),
                 args: vec!["arg1".into(), "arg with space".into()],
                 expected_status: Some(CommandStatus::Success),
@@ -1016,6 +2168,7 @@ $ cmd arg1
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..5),
                 bin: Some(Bin::Name("cmd".into())),
                 env: Env {
                     add: IntoIterator::into_iter([
@@ -1050,6 +2203,7 @@ $ KEY1=VALUE1 KEY2='VALUE2 with space' cmd
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..6),
                 bin: Some(Bin::Name("cmd".into())),
                 expected_status_source: Some(4),
                 expected_status: Some(CommandStatus::Skipped),
@@ -1078,6 +2232,7 @@ $ cmd
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..6),
                 bin: Some(Bin::Name("cmd".into())),
                 expected_status_source: Some(4),
                 expected_status: Some(CommandStatus::Code(-1)),
@@ -1106,6 +2261,7 @@ $ cmd
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..7),
                 bin: Some(Bin::Name("cmd".into())),
                 expected_status: Some(CommandStatus::Success),
                 stderr_to_stdout: true,
@@ -1133,6 +2289,7 @@ Hello World
         let expected = TryCmd {
             steps: vec![Step {
                 id: Some("3".into()),
+                block: Some(2..8),
                 bin: Some(Bin::Name("cmd".into())),
                 expected_status: Some(CommandStatus::Success),
                 stderr_to_stdout: true,
@@ -1162,6 +2319,7 @@ Hello World
             steps: vec![
                 Step {
                     id: Some("3".into()),
+                    block: Some(2..7),
                     bin: Some(Bin::Name("cmd1".into())),
                     expected_status_source: Some(4),
                     expected_status: Some(CommandStatus::Code(1)),
@@ -1173,6 +2331,7 @@ Hello World
                 },
                 Step {
                     id: Some("5".into()),
+                    block: Some(2..7),
                     bin: Some(Bin::Name("cmd2".into())),
                     expected_status: Some(CommandStatus::Success),
                     stderr_to_stdout: true,
@@ -1203,6 +2362,7 @@ $ cmd2
             steps: vec![
                 Step {
                     id: Some("3".into()),
+                    block: Some(2..6),
                     bin: Some(Bin::Name("bare-cmd".into())),
                     expected_status_source: Some(4),
                     expected_status: Some(CommandStatus::Code(1)),
@@ -1214,6 +2374,7 @@ $ cmd2
                 },
                 Step {
                     id: Some("8".into()),
+                    block: Some(7..11),
                     bin: Some(Bin::Name("trycmd-cmd".into())),
                     expected_status_source: Some(9),
                     expected_status: Some(CommandStatus::Code(1)),
@@ -1225,6 +2386,7 @@ $ cmd2
                 },
                 Step {
                     id: Some("18".into()),
+                    block: Some(17..21),
                     bin: Some(Bin::Name("console-cmd".into())),
                     expected_status_source: Some(19),
                     expected_status: Some(CommandStatus::Code(1)),
@@ -1498,6 +2660,7 @@ bar
             step,
             &mut step.expected_stdout_source.clone().unwrap(),
             &mut actual,
+            "",
         )
         .unwrap();
 
@@ -1531,6 +2694,7 @@ bar
             step,
             &mut step.expected_stdout_source.clone().unwrap(),
             &mut actual,
+            "",
         )
         .unwrap();
 
@@ -1564,6 +2728,7 @@ bar
             step,
             &mut step.expected_stdout_source.clone().unwrap(),
             &mut actual,
+            "",
         )
         .unwrap();
 
@@ -1582,6 +2747,546 @@ bar
         std::process::ExitStatus::from_raw(code as u32)
     }
 
+    #[test]
+    fn one_shot_to_toml_skips_defaults() {
+        let expected = r#"bin.name = "cmd"
+args = "--flag 'a b'"
+status.code = 1
+
+[env.add]
+KEY = "VALUE"
+"#;
+        let actual = OneShot {
+            bin: Some(Bin::Name("cmd".into())),
+            args: Args::Joined(JoinedArgs::from_vec(vec!["--flag".into(), "a b".into()])),
+            env: Env {
+                add: IntoIterator::into_iter([("KEY".into(), "VALUE".into())]).collect(),
+                ..Default::default()
+            },
+            status: Some(CommandStatus::Code(1)),
+            ..Default::default()
+        }
+        .to_toml()
+        .unwrap();
+        assert_eq!(expected, actual);
+        assert_eq!(
+            OneShot::parse_toml(&actual).unwrap().status,
+            Some(CommandStatus::Code(1))
+        );
+    }
+
+    #[test]
+    fn step_to_trycmd_roundtrip() {
+        let expected = r#"```console
+$ KEY=VALUE cmd --flag 'a b'
+? failed
+Hello
+World
+```
+"#;
+        let step = Step {
+            bin: Some(Bin::Name("cmd".into())),
+            args: vec!["--flag".into(), "a b".into()],
+            env: Env {
+                add: IntoIterator::into_iter([("KEY".into(), "VALUE".into())]).collect(),
+                ..Default::default()
+            },
+            expected_status: Some(CommandStatus::Failed),
+            expected_stdout: Some(crate::Data::text("Hello\nWorld")),
+            ..Default::default()
+        };
+        let actual = step.to_trycmd().unwrap();
+        assert_eq!(expected, actual);
+
+        let parsed = &TryCmd::parse_trycmd(&actual).unwrap().steps[0];
+        assert_eq!(parsed.bin, step.bin);
+        assert_eq!(parsed.args, step.args);
+        assert_eq!(parsed.env, step.env);
+        assert_eq!(parsed.expected_status, step.expected_status);
+        assert_eq!(parsed.expected_stdout, step.expected_stdout);
+    }
+
+    #[test]
+    fn step_to_trycmd_fence() {
+        let expected = r#"````console
+$ cmd
+```
+````
+"#;
+        let step = Step {
+            bin: Some(Bin::Name("cmd".into())),
+            expected_stdout: Some(crate::Data::text("```")),
+            ..Default::default()
+        };
+        let actual = step.to_trycmd().unwrap();
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn step_to_trycmd_rejects_commands_in_output() {
+        let step = Step {
+            bin: Some(Bin::Name("cmd".into())),
+            expected_stdout: Some(crate::Data::text("$ other")),
+            ..Default::default()
+        };
+        assert!(step.to_trycmd().is_err());
+    }
+
+    #[test]
+    fn parse_include_directive() {
+        assert_eq!(
+            parse_include("<!-- trycmd:include common/setup.trycmd -->\n"),
+            Some("common/setup.trycmd")
+        );
+        assert_eq!(
+            parse_include("  <!--trycmd:include setup.md-->"),
+            Some("setup.md")
+        );
+        assert_eq!(parse_include("<!-- trycmd:includes setup.md -->"), None);
+        assert_eq!(parse_include("trycmd:include setup.md"), None);
+    }
+
+    #[test]
+    fn parse_trycmd_include_without_file() {
+        let input = "<!-- trycmd:include setup.md -->\n";
+        let err = TryCmd::parse_trycmd(input).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("`trycmd:include` is only supported in files on line 1"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn parse_trycmd_literate_config() {
+        let literate = Literate {
+            languages: vec!["sh".into()],
+            prompts: vec!["% ".into()],
+            continuations: vec!["... ".into()],
+            comments: vec!["# ".into()],
+            shell_syntax: false,
+            bare_fences: true,
+        };
+        let actual = TryCmd::parse_trycmd_with(
+            "
+```sh
+# comment
+% cmd
+... arg
+# output
+
+# comment
+% cmd
+```
+
+```console
+$ ignored
+```
+",
+            &literate,
+        )
+        .unwrap();
+        assert_eq!(actual.steps.len(), 2);
+        assert_eq!(actual.steps[0].id.as_deref(), Some("4"));
+        assert_eq!(actual.steps[0].args, vec!["arg".to_owned()]);
+        assert_eq!(
+            actual.steps[0].expected_stdout,
+            Some(crate::Data::text("# output\n"))
+        );
+        assert_eq!(actual.steps[0].expected_stdout_source, Some(6..8));
+        assert_eq!(actual.steps[1].id.as_deref(), Some("9"));
+    }
+
+    #[test]
+    fn front_matter_split() {
+        let (front_matter, rest) = FrontMatter::split(
+            "+++
+prompts = [\"% \"]
++++
+```
+% cmd
+```
+",
+        )
+        .unwrap();
+        assert_eq!(front_matter.prompts, vec!["% ".to_owned()]);
+        assert_eq!(rest, "\n\n\n```\n% cmd\n```\n");
+
+        let (front_matter, rest) = FrontMatter::split("```\n").unwrap();
+        assert_eq!(front_matter, FrontMatter::default());
+        assert_eq!(rest, "```\n");
+
+        assert!(FrontMatter::split("+++\n").is_err());
+    }
+
+    #[test]
+    fn front_matter_apply() {
+        let (front_matter, rest) = FrontMatter::split(
+            "+++
+timeout = \"5s\"
+env.add.KEY = \"file\"
+env.add.OTHER = \"file\"
+fs.sandbox = true
++++
+```
+$ KEY=cmd cmd
+```
+",
+        )
+        .unwrap();
+        let mut actual = TryCmd::parse_trycmd(&rest).unwrap();
+        front_matter.apply(&mut actual);
+        assert_eq!(actual.fs.sandbox, Some(true));
+        let step = &actual.steps[0];
+        assert_eq!(step.timeout, Some(std::time::Duration::from_secs(5)));
+        assert_eq!(step.env.add["KEY"], "cmd");
+        assert_eq!(step.env.add["OTHER"], "file");
+    }
+
+    #[test]
+    fn parse_trycmd_file_blocks() {
+        let actual = TryCmd::parse_trycmd(
+            "
+```toml,file=config.toml
+a = 1
+```
+```
+$ cmd
+```
+```toml,expect-file=config.toml
+a = 2
+b = 3
+```
+",
+        )
+        .unwrap();
+        let step = &actual.steps[0];
+        assert_eq!(
+            step.files,
+            vec![FileBlock {
+                path: "config.toml".into(),
+                content: "a = 1\n".into(),
+                source: 3..4,
+            }]
+        );
+        assert_eq!(
+            step.expected_files,
+            vec![FileBlock {
+                path: "config.toml".into(),
+                content: "a = 2\nb = 3\n".into(),
+                source: 9..11,
+            }]
+        );
+
+        let err = TryCmd::parse_trycmd("```text,expect-file=out.txt\n```\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("`expect-file=out.txt` on line 1 must follow a command"));
+        let err = TryCmd::parse_trycmd("```text,expect-mocks\n```\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("`expect-mocks` on line 1 must follow a command"));
+        let err = TryCmd::parse_trycmd("```text,file=in.txt\n```\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("`file=in.txt` on line 1 must be followed by a command"));
+    }
+
+    #[test]
+    fn env_get_hermetic() {
+        let env = Env {
+            hermetic: Some(true),
+            add: IntoIterator::into_iter([("TZ".into(), "EST".into())]).collect(),
+            ..Default::default()
+        };
+        assert_eq!(env.get("TZ").as_deref(), Some("EST"));
+        assert_eq!(env.get("LC_ALL").as_deref(), Some("C"));
+        assert_eq!(env.get("PATH"), std::env::var("PATH").ok());
+        assert_eq!(env.get("CARGO_PKG_NAME"), None);
+
+        let env = Env::default();
+        assert_eq!(env.get("CARGO_PKG_NAME").as_deref(), Some("trycmd"));
+    }
+
+    #[test]
+    fn layout_siblings() {
+        let path = std::path::Path::new("tests/cmd/basic.toml");
+        assert_eq!(
+            Layout::Files.sibling(path, "stdout"),
+            std::path::Path::new("tests/cmd/basic.stdout")
+        );
+
+        let path = std::path::Path::new("tests/cmd/basic/cmd.toml");
+        assert_eq!(
+            Layout::Dir.sibling(path, "stdout"),
+            std::path::Path::new("tests/cmd/basic/stdout")
+        );
+        assert_eq!(
+            Layout::Dir.sibling(path, "json.stdout"),
+            std::path::Path::new("tests/cmd/basic/json.stdout")
+        );
+        // Only how the case was found decides its layout, not its name
+        assert_eq!(
+            Layout::Files.sibling(path, "stdout"),
+            std::path::Path::new("tests/cmd/basic/cmd.stdout")
+        );
+    }
+
+    #[test]
+    fn step_to_shell_words() {
+        let step = Step {
+            bin: Some(Bin::Path("/bin/cmd".into())),
+            args: vec!["--flag".into(), "two words".into()],
+            env: Env {
+                add: IntoIterator::into_iter([("KEY".into(), "it's".into())]).collect(),
+                remove: vec!["HOME".into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            step.to_shell_words(),
+            [
+                "env",
+                "-u",
+                "HOME",
+                r#"KEY="it's""#,
+                "/bin/cmd",
+                "--flag",
+                "'two words'"
+            ]
+        );
+
+        let step = Step {
+            bin: Some(Bin::Name("cmd".into())),
+            env: Env {
+                inherit: Some(false),
+                remove: vec!["HOME".into()],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(step.to_shell_words(), ["env", "-i", "cmd"]);
+    }
+
+    #[test]
+    fn parse_trycmd_captures() {
+        let actual = TryCmd::parse_trycmd(
+            "
+```
+$ cmd
+=> ID id: (?<ID>\\d+)
+? 1
+=> ALL
+id: 5
+```
+",
+        )
+        .unwrap();
+        let step = &actual.steps[0];
+        assert_eq!(
+            step.captures,
+            vec![
+                Capture {
+                    name: "ID".into(),
+                    regex: Some("id: (?<ID>\\d+)".into()),
+                },
+                Capture {
+                    name: "ALL".into(),
+                    regex: None,
+                },
+            ]
+        );
+        assert_eq!(step.expected_status, Some(CommandStatus::Code(1)));
+        assert_eq!(step.expected_stdout, Some(crate::Data::text("id: 5")));
+        assert_eq!(step.expected_stdout_source, Some(7..8));
+    }
+
+    #[test]
+    fn parse_trycmd_step_control() {
+        let actual = TryCmd::parse_trycmd(
+            "
+```console,continue-on-error
+$ cmd
+```
+
+```console
+$ cmd
+```
+
+```console,always-run
+$ cmd
+```
+",
+        )
+        .unwrap();
+        let flags = actual
+            .steps
+            .iter()
+            .map(|s| (s.continue_on_error, s.always_run))
+            .collect::<Vec<_>>();
+        assert_eq!(flags, vec![(true, false), (false, false), (false, true)]);
+    }
+
+    #[test]
+    fn capture_extract() {
+        let capture = |regex: Option<&str>| Capture {
+            name: "ID".into(),
+            regex: regex.map(|r| r.into()),
+        };
+        assert_eq!(capture(None).extract(b"a 1\n\n").unwrap(), "a 1");
+        assert_eq!(capture(Some(r"\d")).extract(b"a 1\n").unwrap(), "1");
+        assert_eq!(
+            capture(Some(r"(\w) (?<ID>\d)")).extract(b"a 1\n").unwrap(),
+            "1"
+        );
+        assert!(capture(Some(r"\d")).extract(b"a\n").is_err());
+        assert!(capture(Some("(")).extract(b"a\n").is_err());
+        assert!(Capture {
+            name: "id".into(),
+            regex: None
+        }
+        .placeholder()
+        .is_err());
+        assert!(Capture {
+            name: "CWD".into(),
+            regex: None
+        }
+        .placeholder()
+        .is_err());
+        let placeholder = capture(None).placeholder().unwrap();
+        assert_eq!(placeholder, "[ID]");
+        assert!(std::ptr::eq(
+            placeholder,
+            capture(None).placeholder().unwrap()
+        ));
+    }
+
+    #[test]
+    fn extract_doc_comments_keeps_line_numbers() {
+        let expected = "```console
+$ cmd
+
+```
+
+
+
+```
+";
+        let actual = extract_doc_comments(
+            "    /// ```console
+    /// $ cmd
+    ///
+    /// ```
+fn main() {}
+//// not docs
+//!
+//!```
+",
+        );
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn parse_rst() {
+        let blocks = extract_rst(
+            "Title
+
+.. code-block:: console
+   :caption: Example
+
+   $ cmd arg
+   foo
+
+   bar
+
+Next
+",
+        );
+        assert_eq!(blocks.len(), 1);
+        let actual = TryCmd::parse_trycmd(&blocks[0]).unwrap();
+        assert_eq!(actual.steps.len(), 1);
+        assert_eq!(actual.steps[0].id.as_deref(), Some("6"));
+        assert_eq!(actual.steps[0].args, vec!["arg".to_owned()]);
+        assert_eq!(
+            actual.steps[0].expected_stdout,
+            Some(crate::Data::text("foo\n\nbar\n"))
+        );
+        assert_eq!(actual.steps[0].expected_stdout_source, Some(7..11));
+    }
+
+    #[test]
+    fn parse_adoc() {
+        let actual = TryCmd::parse_trycmd(&extract_adoc(
+            "= Title
+
+[source,rust]
+----
+fn main() {}
+----
+
+.Example
+[source,console]
+----
+$ cmd arg
+foo
+----
+",
+        ))
+        .unwrap();
+        assert_eq!(actual.steps.len(), 1);
+        assert_eq!(actual.steps[0].id.as_deref(), Some("11"));
+        assert_eq!(actual.steps[0].args, vec!["arg".to_owned()]);
+        assert_eq!(
+            actual.steps[0].expected_stdout,
+            Some(crate::Data::text("foo"))
+        );
+        assert_eq!(actual.steps[0].expected_stdout_source, Some(12..13));
+    }
+
+    #[test]
+    fn overwrite_rs_keeps_prefix() {
+        let expected = r#"
+    /// ```console
+    /// $ cmd arg
+    /// ? 1
+    /// foo
+    ///
+    /// bar
+    /// ```
+"#;
+
+        let mut actual = r"
+    /// ```console
+    /// $ cmd arg
+    /// ```
+"
+        .to_owned();
+
+        let step = &TryCmd::parse_trycmd(&extract_doc_comments(&actual))
+            .unwrap()
+            .steps[0];
+        let prefix = cmd_prefix(&actual, 3, &Literate::default().prompts).to_owned();
+        assert_eq!(prefix, "    /// ");
+        let mut line_nums = step.expected_stdout_source.clone().unwrap();
+        overwrite_trycmd_status(
+            Some(exit_code_to_status(1)),
+            step,
+            &mut line_nums,
+            &mut actual,
+            &prefix,
+        )
+        .unwrap();
+        replace_lines(
+            &mut actual,
+            line_nums,
+            &prefix_lines("foo\n\nbar\n", &prefix),
+        )
+        .unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
     #[test]
     fn exit_code_to_status_works() {
         assert_eq!(exit_code_to_status(42).code(), Some(42));
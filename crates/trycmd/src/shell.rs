@@ -0,0 +1,931 @@
+//! Subset of shell syntax for literate command lines
+//!
+//! Supported:
+//! - `'...'` and `"..."` quoting and `\` escapes
+//! - Leading `KEY=VALUE` words for the environment
+//! - `$VAR` and `${VAR}` from the environment
+//! - `[ROOT]` and `[CWD]` for the sandbox
+//! - `$(...)` command substitution, without any word splitting
+//! - `|` pipelines
+//! - `>`, `>>`, `2>`, and `2>>` redirections into the sandbox
+
+use std::collections::BTreeMap;
+
+const BUILTINS: &[&str] = &["[ROOT]", "[CWD]"];
+
+/// Longest first so `2>>` isn't read as `2>`
+const REDIRECTS: &[(&str, Fd, bool)] = &[
+    ("2>>", Fd::Stderr, true),
+    ("2>", Fd::Stderr, false),
+    (">>", Fd::Stdout, true),
+    (">", Fd::Stdout, false),
+];
+
+/// A command line, parsed but not yet expanded
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct CommandLine {
+    /// Commands connected by `|`
+    stages: Vec<Stage>,
+}
+
+impl CommandLine {
+    pub(crate) fn parse(s: &str) -> Result<Self, crate::Error> {
+        let mut parser = Parser { s, pos: 0 };
+        let cmdline = parser.parse_cmdline(false)?;
+        if cmdline.stages.is_empty() {
+            return Err("No bin specified".into());
+        }
+        Ok(cmdline)
+    }
+
+    /// Expand each command of the pipeline
+    pub(crate) fn expand(&self, cx: &Context<'_>) -> Result<Vec<Expanded>, crate::Error> {
+        self.stages.iter().map(|stage| stage.expand(cx)).collect()
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+struct Stage {
+    words: Vec<Word>,
+    stdout: Option<RedirectTarget>,
+    stderr: Option<RedirectTarget>,
+}
+
+impl Stage {
+    fn expand(&self, cx: &Context<'_>) -> Result<Expanded, crate::Error> {
+        let mut words = self.words.iter().peekable();
+        let mut env = BTreeMap::new();
+        while let Some(word) = words.next_if(|w| w.assignment.is_some()) {
+            let name = word.assignment.clone().expect("checked by `next_if`");
+            env.insert(name, word.expand_value(cx)?);
+        }
+        let bin = words
+            .next()
+            .ok_or_else(|| crate::Error::new("No bin specified"))?
+            .expand(cx)?;
+        let args = words.map(|w| w.expand(cx)).collect::<Result<_, _>>()?;
+        let redirects = Redirects {
+            stdout: self.stdout.as_ref().map(|r| r.expand(cx)).transpose()?,
+            stderr: self.stderr.as_ref().map(|r| r.expand(cx)).transpose()?,
+        };
+        Ok(Expanded {
+            env,
+            bin,
+            args,
+            redirects,
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Fd {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RedirectTarget {
+    path: Word,
+    append: bool,
+}
+
+impl RedirectTarget {
+    fn expand(&self, cx: &Context<'_>) -> Result<Redirect, crate::Error> {
+        let path = self.path.expand(cx)?;
+        Ok(Redirect {
+            path: cx.sandbox_path(&path)?,
+            append: self.append,
+        })
+    }
+}
+
+/// What to run for one command of a [`CommandLine`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Expanded {
+    pub(crate) env: BTreeMap<String, String>,
+    pub(crate) bin: String,
+    pub(crate) args: Vec<String>,
+    pub(crate) redirects: Redirects,
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub(crate) struct Redirects {
+    pub(crate) stdout: Option<Redirect>,
+    pub(crate) stderr: Option<Redirect>,
+}
+
+impl Redirects {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.stdout.is_none() && self.stderr.is_none()
+    }
+
+    /// Render as `/bin/sh` words
+    pub(crate) fn to_shell_words(&self) -> Vec<String> {
+        let mut words = Vec::new();
+        for (fd, redirect) in [("", &self.stdout), ("2", &self.stderr)] {
+            if let Some(redirect) = redirect {
+                let op = if redirect.append { ">>" } else { ">" };
+                words.push(format!(
+                    "{}{} {}",
+                    fd,
+                    op,
+                    quote(&redirect.path.to_string_lossy())
+                ));
+            }
+        }
+        words
+    }
+}
+
+/// A file in the sandbox to write a stream to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Redirect {
+    pub(crate) path: std::path::PathBuf,
+    pub(crate) append: bool,
+}
+
+impl Redirect {
+    fn open(&self) -> Result<std::fs::File, crate::Error> {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(self.append)
+            .truncate(!self.append)
+            .open(&self.path)
+            .map_err(|e| format!("Failed to write {}: {}", self.path.display(), e).into())
+    }
+}
+
+/// Commands to run before a step, piping into it
+pub(crate) struct Pipeline {
+    /// Each command with its redirections, in order
+    pub(crate) upstream: Vec<(crate::schema::Step, Redirects)>,
+    /// Redirections for the step itself
+    pub(crate) redirects: Redirects,
+}
+
+impl Pipeline {
+    /// Run the pipeline, ending with `step`
+    ///
+    /// Like a shell, every command runs at once with each `stdout` streamed into the next
+    /// command's `stdin`.  Like `set -o pipefail`, the status is from the last command to fail, if
+    /// any.  `stderr` from earlier commands comes before the output of `step`.
+    pub(crate) fn output(
+        &self,
+        step: &crate::schema::Step,
+        cwd: Option<&std::path::Path>,
+    ) -> Result<std::process::Output, crate::Error> {
+        let stdin = step
+            .stdin
+            .as_ref()
+            .map(|d| d.to_bytes())
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        let stages = self
+            .upstream
+            .iter()
+            .map(|(stage, redirects)| (stage, redirects))
+            .chain(std::iter::once((step, &self.redirects)));
+
+        let start = std::time::Instant::now();
+        let mut running: Vec<Running> = Vec::new();
+        for (stage, redirects) in stages {
+            let stage_stdin = match running.last_mut() {
+                Some(upstream) => match upstream.child.stdout.take() {
+                    Some(stdout) => stdout.into(),
+                    // Redirected to a file
+                    None => std::process::Stdio::null(),
+                },
+                None if stdin.is_some() => std::process::Stdio::piped(),
+                None => std::process::Stdio::null(),
+            };
+            match Running::spawn(stage, redirects, cwd, stage_stdin) {
+                Ok(stage) => running.push(stage),
+                Err(err) => {
+                    for stage in running {
+                        stage.kill();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        let stdout = running
+            .last_mut()
+            .and_then(|last| last.child.stdout.take())
+            .map(threaded_read);
+        let writer = match (stdin, running[0].child.stdin.take()) {
+            (Some(data), Some(mut pipe)) => Some(std::thread::spawn(move || {
+                // A command may exit without reading everything
+                let _ = std::io::Write::write_all(&mut pipe, &data);
+            })),
+            _ => None,
+        };
+
+        let mut failed = None;
+        let mut status = None;
+        let mut passthrough = Vec::new();
+        let mut stderr = Vec::new();
+        let last = running.len() - 1;
+        for (i, stage) in running.into_iter().enumerate() {
+            let (stage_status, stage_stderr) = stage.wait(start)?;
+            if !stage_status.success() {
+                failed = Some(stage_status);
+            }
+            status = Some(stage_status);
+            if i == last {
+                stderr = stage_stderr;
+            } else {
+                passthrough.extend(stage_stderr);
+            }
+        }
+        if let Some(writer) = writer {
+            writer.join().expect("writing `stdin` doesn't panic");
+        }
+        let stdout = join(stdout)?;
+
+        let status = failed
+            .or(status)
+            .expect("parsing ensures at least one command");
+        if step.stderr_to_stdout {
+            passthrough.extend(stdout);
+            passthrough.extend(stderr);
+            Ok(std::process::Output {
+                status,
+                stdout: passthrough,
+                stderr: Vec::new(),
+            })
+        } else {
+            passthrough.extend(stderr);
+            Ok(std::process::Output {
+                status,
+                stdout,
+                stderr: passthrough,
+            })
+        }
+    }
+}
+
+/// One command of a running [`Pipeline`]
+struct Running {
+    child: std::process::Child,
+    stderr: Option<Stream>,
+    timeout: Option<std::time::Duration>,
+}
+
+impl Running {
+    fn spawn(
+        stage: &crate::schema::Step,
+        redirects: &Redirects,
+        cwd: Option<&std::path::Path>,
+        stdin: std::process::Stdio,
+    ) -> Result<Self, crate::Error> {
+        let mut cmd = stage.to_std_command(cwd)?;
+        cmd.stdin(stdin);
+        cmd.stdout(match &redirects.stdout {
+            Some(redirect) => redirect.open()?.into(),
+            None => std::process::Stdio::piped(),
+        });
+        cmd.stderr(match &redirects.stderr {
+            Some(redirect) => redirect.open()?.into(),
+            None => std::process::Stdio::piped(),
+        });
+        let mut child = cmd.spawn().map_err(|e| e.to_string())?;
+        let stderr = child.stderr.take().map(threaded_read);
+        Ok(Self {
+            child,
+            stderr,
+            timeout: stage.timeout,
+        })
+    }
+
+    /// Wait for the command to exit, killing it if it runs past its timeout
+    fn wait(
+        mut self,
+        start: std::time::Instant,
+    ) -> Result<(std::process::ExitStatus, Vec<u8>), crate::Error> {
+        let status = match self.timeout {
+            Some(timeout) => {
+                let remaining = timeout.saturating_sub(start.elapsed());
+                match wait_timeout::ChildExt::wait_timeout(&mut self.child, remaining) {
+                    Ok(Some(status)) => Ok(status),
+                    Ok(None) => {
+                        let _ = self.child.kill();
+                        self.child.wait()
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            None => self.child.wait(),
+        }
+        .map_err(|e| e.to_string())?;
+        let stderr = join(self.stderr)?;
+        Ok((status, stderr))
+    }
+
+    fn kill(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+type Stream = std::thread::JoinHandle<std::io::Result<Vec<u8>>>;
+
+fn threaded_read(mut input: impl std::io::Read + Send + 'static) -> Stream {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        input.read_to_end(&mut buffer).map(|_| buffer)
+    })
+}
+
+fn join(stream: Option<Stream>) -> Result<Vec<u8>, crate::Error> {
+    match stream {
+        Some(stream) => Ok(stream
+            .join()
+            .expect("reading a stream doesn't panic")
+            .map_err(|e| e.to_string())?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Quote `s` as a `/bin/sh` word, if needed
+pub(crate) fn quote(s: &str) -> String {
+    shlex::try_quote(s)
+        .map(|s| s.into_owned())
+        .unwrap_or_else(|_| s.to_owned())
+}
+
+/// Values available for expansion
+pub(crate) struct Context<'c> {
+    pub(crate) env: &'c crate::schema::Env,
+    pub(crate) root: Option<&'c std::path::Path>,
+    pub(crate) cwd: Option<&'c std::path::Path>,
+    /// Whether `root` may be written to
+    pub(crate) sandbox: bool,
+    pub(crate) bins: &'c crate::BinRegistry,
+    pub(crate) timeout: Option<std::time::Duration>,
+}
+
+impl Context<'_> {
+    fn var(&self, name: &str) -> Result<String, crate::Error> {
+        self.env
+            .get(name)
+            .ok_or_else(|| format!("Undefined variable `${}`", name).into())
+    }
+
+    fn builtin(&self, name: &str) -> Result<String, crate::Error> {
+        let path = match name {
+            "[ROOT]" => self.root,
+            "[CWD]" => self.cwd,
+            _ => unreachable!("unknown builtin {}", name),
+        };
+        path.map(|p| p.display().to_string())
+            .ok_or_else(|| format!("`{}` is unavailable without a sandbox", name).into())
+    }
+
+    /// Resolve a redirection target, ensuring it is inside the sandbox
+    fn sandbox_path(&self, path: &str) -> Result<std::path::PathBuf, crate::Error> {
+        let root = match (self.root, self.sandbox) {
+            (Some(root), true) => root,
+            _ => return Err(format!("Redirecting to `{}` requires a sandbox", path).into()),
+        };
+        resolve(root, self.cwd.unwrap_or(root), path)
+    }
+
+    /// Run `$(...)`, returning its `stdout` without trailing newlines
+    fn substitute(&self, cmdline: &CommandLine) -> Result<String, crate::Error> {
+        let mut expanded = cmdline.expand(self)?;
+        if expanded.len() != 1 || !expanded[0].redirects.is_empty() {
+            return Err("Only a single command is supported in `$(...)`".into());
+        }
+        let expanded = expanded.pop().expect("checked for one command");
+        let stdout = if expanded.bin == "cat" && !self.bins.contains("cat") {
+            // Built-in for portability
+            let mut stdout = String::new();
+            for arg in &expanded.args {
+                let path = match (self.root, self.cwd) {
+                    (Some(root), cwd) => resolve(root, cwd.unwrap_or(root), arg)?,
+                    (None, Some(cwd)) => cwd.join(arg),
+                    (None, None) => arg.into(),
+                };
+                let content = std::fs::read_to_string(&path)
+                    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+                stdout.push_str(&content);
+            }
+            stdout
+        } else {
+            let mut env = self.env.clone();
+            env.add.extend(expanded.env);
+            let step = crate::schema::Step {
+                bin: Some(self.bins.resolve_name(&expanded.bin)),
+                args: expanded.args,
+                env,
+                timeout: self.timeout,
+                ..Default::default()
+            };
+            let output = step
+                .to_command(self.cwd)?
+                .output()
+                .map_err(|e| e.to_string())?;
+            if !output.status.success() {
+                return Err(format!(
+                    "`$({} ...)` failed with {}: {}",
+                    expanded.bin,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim_end()
+                )
+                .into());
+            }
+            String::from_utf8(output.stdout)
+                .map_err(|_| format!("`$({} ...)` output is not UTF-8", expanded.bin))?
+        };
+        Ok(stdout.trim_end_matches(['\r', '\n']).to_owned())
+    }
+}
+
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+struct Word {
+    /// `KEY` for a `KEY=VALUE` word
+    assignment: Option<String>,
+    parts: Vec<Part>,
+}
+
+impl Word {
+    fn expand(&self, cx: &Context<'_>) -> Result<String, crate::Error> {
+        let value = self.expand_value(cx)?;
+        match &self.assignment {
+            Some(name) => Ok(format!("{}={}", name, value)),
+            None => Ok(value),
+        }
+    }
+
+    /// Expand without any `KEY=` prefix
+    fn expand_value(&self, cx: &Context<'_>) -> Result<String, crate::Error> {
+        let mut expanded = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => expanded.push_str(literal),
+                Part::Var(name) => expanded.push_str(&cx.var(name)?),
+                Part::Builtin(name) => expanded.push_str(&cx.builtin(name)?),
+                Part::Substitution(cmdline) => expanded.push_str(&cx.substitute(cmdline)?),
+            }
+        }
+        Ok(expanded)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// `$VAR` or `${VAR}`
+    Var(String),
+    /// `[ROOT]` or `[CWD]`
+    Builtin(&'static str),
+    /// `$(...)`
+    Substitution(CommandLine),
+}
+
+struct Parser<'s> {
+    s: &'s str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn rest(&self) -> &str {
+        &self.s[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn parse_cmdline(&mut self, nested: bool) -> Result<CommandLine, crate::Error> {
+        let mut stages = Vec::new();
+        let mut stage = Stage::default();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None if nested => return Err("Unterminated `$(`".into()),
+                None => break,
+                Some(')') if nested => {
+                    self.bump();
+                    break;
+                }
+                Some('|') => {
+                    self.bump();
+                    if !has_bin(&stage) {
+                        return Err("Expected a command before `|`".into());
+                    }
+                    stages.push(std::mem::take(&mut stage));
+                }
+                Some('<') => return Err("Unsupported `<`".into()),
+                Some(_) => {
+                    if let Some((fd, append)) = self.parse_redirect() {
+                        self.skip_whitespace();
+                        if self.peek() == Some('&') {
+                            return Err("Unsupported `>&`".into());
+                        }
+                        let path = self.parse_word(nested)?;
+                        if path == Word::default() {
+                            return Err("Expected a file after `>`".into());
+                        }
+                        let target = Some(RedirectTarget { path, append });
+                        match fd {
+                            Fd::Stdout => stage.stdout = target,
+                            Fd::Stderr => stage.stderr = target,
+                        }
+                    } else {
+                        stage.words.push(self.parse_word(nested)?);
+                    }
+                }
+            }
+        }
+        if stage != Stage::default() || !stages.is_empty() {
+            if !has_bin(&stage) {
+                return Err(if stages.is_empty() {
+                    "No bin specified".into()
+                } else {
+                    "Expected a command after `|`".into()
+                });
+            }
+            stages.push(stage);
+        }
+        Ok(CommandLine { stages })
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.bump();
+        }
+    }
+
+    fn parse_redirect(&mut self) -> Option<(Fd, bool)> {
+        let (op, fd, append) = REDIRECTS
+            .iter()
+            .find(|(op, ..)| self.rest().starts_with(op))?;
+        self.pos += op.len();
+        Some((*fd, *append))
+    }
+
+    fn parse_word(&mut self, nested: bool) -> Result<Word, crate::Error> {
+        let mut word = Word::default();
+        let mut literal = String::new();
+        // Whether this could still be `KEY=VALUE`
+        let mut is_name = true;
+        while let Some(c) = self.peek() {
+            match c {
+                c if c.is_whitespace() => break,
+                '|' | '>' | '<' => break,
+                ')' if nested => break,
+                '=' if is_name && word.assignment.is_none() && is_var_name(&literal) => {
+                    self.bump();
+                    word.assignment = Some(std::mem::take(&mut literal));
+                    is_name = false;
+                }
+                '\'' => {
+                    is_name = false;
+                    self.bump();
+                    loop {
+                        match self.bump() {
+                            Some('\'') => break,
+                            Some(c) => literal.push(c),
+                            None => return Err("Unterminated `'`".into()),
+                        }
+                    }
+                }
+                '"' => {
+                    is_name = false;
+                    self.bump();
+                    loop {
+                        match self.peek() {
+                            Some('"') => {
+                                self.bump();
+                                break;
+                            }
+                            Some('\\') => {
+                                self.bump();
+                                match self.bump() {
+                                    Some(c @ ('"' | '\\' | '$' | '`')) => literal.push(c),
+                                    Some(c) => {
+                                        literal.push('\\');
+                                        literal.push(c);
+                                    }
+                                    None => return Err("Unterminated `\"`".into()),
+                                }
+                            }
+                            Some(_) => self.parse_expansion(&mut word, &mut literal)?,
+                            None => return Err("Unterminated `\"`".into()),
+                        }
+                    }
+                }
+                '\\' => {
+                    is_name = false;
+                    self.bump();
+                    if let Some(c) = self.bump() {
+                        literal.push(c);
+                    }
+                }
+                _ => {
+                    if !(c.is_ascii_alphanumeric() || c == '_') {
+                        is_name = false;
+                    }
+                    self.parse_expansion(&mut word, &mut literal)?;
+                }
+            }
+        }
+        if !literal.is_empty() {
+            word.parts.push(Part::Literal(literal));
+        }
+        Ok(word)
+    }
+
+    /// Parse a character that might start a `$` or `[` expansion
+    fn parse_expansion(
+        &mut self,
+        word: &mut Word,
+        literal: &mut String,
+    ) -> Result<(), crate::Error> {
+        if let Some(builtin) = BUILTINS.iter().find(|b| self.rest().starts_with(*b)) {
+            self.pos += builtin.len();
+            flush(word, literal);
+            word.parts.push(Part::Builtin(builtin));
+            return Ok(());
+        }
+
+        let c = self.bump().expect("called with a character");
+        if c != '$' {
+            literal.push(c);
+            return Ok(());
+        }
+        match self.peek() {
+            Some('{') => {
+                self.bump();
+                let end = self
+                    .rest()
+                    .find('}')
+                    .ok_or_else(|| crate::Error::new("Unterminated `${`"))?;
+                let name = self.rest()[..end].to_owned();
+                if !is_var_name(&name) {
+                    return Err(format!("Invalid variable `${{{}}}`", name).into());
+                }
+                self.pos += end + 1;
+                flush(word, literal);
+                word.parts.push(Part::Var(name));
+            }
+            Some('(') => {
+                self.bump();
+                let cmdline = self.parse_cmdline(true)?;
+                if cmdline.stages.is_empty() {
+                    return Err("Empty `$()`".into());
+                }
+                flush(word, literal);
+                word.parts.push(Part::Substitution(cmdline));
+            }
+            Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+                let end = self
+                    .rest()
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(self.rest().len());
+                let name = self.rest()[..end].to_owned();
+                self.pos += end;
+                flush(word, literal);
+                word.parts.push(Part::Var(name));
+            }
+            _ => literal.push('$'),
+        }
+        Ok(())
+    }
+}
+
+/// Join `path` to `cwd`, ensuring the result stays within `root`
+pub(crate) fn resolve(
+    root: &std::path::Path,
+    cwd: &std::path::Path,
+    path: &str,
+) -> Result<std::path::PathBuf, crate::Error> {
+    let mut resolved = cwd.to_owned();
+    for component in std::path::Path::new(path).components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    if resolved.starts_with(root) {
+        Ok(resolved)
+    } else {
+        Err(format!("`{}` is outside of the sandbox", path).into())
+    }
+}
+
+fn has_bin(stage: &Stage) -> bool {
+    stage.words.iter().any(|w| w.assignment.is_none())
+}
+
+fn flush(word: &mut Word, literal: &mut String) {
+    if !literal.is_empty() {
+        word.parts.push(Part::Literal(std::mem::take(literal)));
+    }
+}
+
+fn is_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|c| c.is_ascii_alphabetic() || c == '_')
+        .unwrap_or(false)
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn expand(s: &str, env: &[(&str, &str)]) -> Result<Expanded, crate::Error> {
+        let mut expanded = expand_pipeline(s, env)?;
+        assert_eq!(expanded.len(), 1);
+        Ok(expanded.pop().unwrap())
+    }
+
+    fn expand_pipeline(s: &str, env: &[(&str, &str)]) -> Result<Vec<Expanded>, crate::Error> {
+        let env = crate::schema::Env {
+            inherit: Some(false),
+            add: env
+                .iter()
+                .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+                .collect(),
+            ..Default::default()
+        };
+        let bins = crate::BinRegistry::new();
+        let cx = Context {
+            env: &env,
+            root: Some(std::path::Path::new("/root")),
+            cwd: Some(std::path::Path::new("/root/cwd")),
+            sandbox: true,
+            bins: &bins,
+            timeout: None,
+        };
+        CommandLine::parse(s)?.expand(&cx)
+    }
+
+    #[test]
+    fn quoting() {
+        let actual = expand(r#"cmd 'a $B' "c \"$B\"" d\ e"#, &[("B", "b")]).unwrap();
+        assert_eq!(actual.bin, "cmd");
+        assert_eq!(actual.args, vec!["a $B", "c \"b\"", "d e"]);
+    }
+
+    #[test]
+    fn vars() {
+        let actual = expand("cmd $A ${A}x $ [ROOT] '[CWD]' \"[CWD]/f\"", &[("A", "a")]).unwrap();
+        assert_eq!(
+            actual.args,
+            vec!["a", "ax", "$", "/root", "[CWD]", "/root/cwd/f"]
+        );
+    }
+
+    #[test]
+    fn assignments() {
+        let actual = expand("A=1 B=\"$C\" cmd D=2", &[("C", "c")]).unwrap();
+        assert_eq!(
+            actual.env,
+            [("A".to_owned(), "1".to_owned()), ("B".into(), "c".into())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+        assert_eq!(actual.bin, "cmd");
+        assert_eq!(actual.args, vec!["D=2"]);
+    }
+
+    #[test]
+    fn undefined_var() {
+        assert!(expand("cmd $UNDEFINED", &[]).is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(CommandLine::parse("A=1").is_err());
+        assert!(CommandLine::parse("cmd 'a").is_err());
+        assert!(CommandLine::parse("cmd \"a").is_err());
+        assert!(CommandLine::parse("cmd $(a").is_err());
+        assert!(CommandLine::parse("cmd $()").is_err());
+        assert!(CommandLine::parse("cmd ${a-b}").is_err());
+        assert!(CommandLine::parse("cmd |").is_err());
+        assert!(CommandLine::parse("| cmd").is_err());
+        assert!(CommandLine::parse("A=1 | cmd").is_err());
+        assert!(CommandLine::parse("cmd >").is_err());
+        assert!(CommandLine::parse("cmd > | cmd").is_err());
+        assert!(CommandLine::parse("cmd 2>&1").is_err());
+        assert!(CommandLine::parse("cmd < file").is_err());
+        assert!(CommandLine::parse("cmd $(a |)").is_err());
+    }
+
+    #[test]
+    fn substitution_parse() {
+        let actual = CommandLine::parse("cmd \"$(cat 'a b')\"x").unwrap();
+        assert_eq!(
+            actual.stages[0].words[1].parts,
+            vec![
+                Part::Substitution(CommandLine {
+                    stages: vec![Stage {
+                        words: vec![
+                            Word {
+                                assignment: None,
+                                parts: vec![Part::Literal("cat".into())],
+                            },
+                            Word {
+                                assignment: None,
+                                parts: vec![Part::Literal("a b".into())],
+                            },
+                        ],
+                        ..Default::default()
+                    }],
+                }),
+                Part::Literal("x".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn pipeline() {
+        let actual = expand_pipeline("A=1 export --all | import '|' a|b", &[]).unwrap();
+        assert_eq!(actual.len(), 3);
+        assert_eq!(actual[0].bin, "export");
+        assert_eq!(actual[0].args, vec!["--all"]);
+        assert_eq!(actual[0].env.len(), 1);
+        assert_eq!(actual[1].bin, "import");
+        assert_eq!(actual[1].args, vec!["|", "a"]);
+        assert!(actual[1].env.is_empty());
+        assert_eq!(actual[2].bin, "b");
+    }
+
+    #[test]
+    fn redirects() {
+        let actual = expand("cmd a>out.txt 2>> ../err.txt '>' b", &[]).unwrap();
+        assert_eq!(actual.args, vec!["a", ">", "b"]);
+        assert_eq!(
+            actual.redirects,
+            Redirects {
+                stdout: Some(Redirect {
+                    path: "/root/cwd/out.txt".into(),
+                    append: false,
+                }),
+                stderr: Some(Redirect {
+                    path: "/root/err.txt".into(),
+                    append: true,
+                }),
+            }
+        );
+    }
+
+    #[test]
+    fn redirect_outside_sandbox() {
+        assert!(expand("cmd > ../../out.txt", &[]).is_err());
+        assert!(expand("cmd > /tmp/out.txt", &[]).is_err());
+        assert!(expand("cmd > ../..", &[]).is_err());
+    }
+
+    #[test]
+    fn cat_outside_sandbox() {
+        let err = expand("cmd $(cat ../../etc/passwd)", &[]).unwrap_err();
+        assert!(
+            err.to_string().contains("outside of the sandbox"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn registered_cat() {
+        let env = crate::schema::Env::default();
+        let mut bins = crate::BinRegistry::new();
+        bins.register_bin(
+            "cat".to_owned(),
+            crate::schema::Bin::Path("/nonexistent/cat".into()),
+        );
+        let cx = Context {
+            env: &env,
+            root: Some(std::path::Path::new("/root")),
+            cwd: Some(std::path::Path::new("/root/cwd")),
+            sandbox: true,
+            bins: &bins,
+            timeout: None,
+        };
+        let err = CommandLine::parse("cmd $(cat ../../etc/passwd)")
+            .unwrap()
+            .expand(&cx)
+            .unwrap_err();
+        assert!(
+            !err.to_string().contains("outside of the sandbox"),
+            "{}",
+            err
+        );
+    }
+}
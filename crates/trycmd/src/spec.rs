@@ -3,20 +3,32 @@ use std::collections::BTreeMap;
 #[derive(Debug)]
 pub(crate) struct RunnerSpec {
     cases: Vec<CaseSpec>,
+    exclude: Vec<std::path::PathBuf>,
     include: Option<Vec<String>>,
+    /// Only these cases, from `TRYCMD_RERUN=failed`
+    rerun: Option<std::collections::BTreeSet<std::path::PathBuf>>,
     default_bin: Option<crate::schema::Bin>,
     timeout: Option<std::time::Duration>,
     env: crate::schema::Env,
+    literate: crate::schema::Literate,
+    keep_sandbox: crate::KeepSandbox,
+    /// Reported when run, if invalid
+    shard: Option<Result<Shard, crate::Error>>,
 }
 
 impl RunnerSpec {
     pub(crate) fn new() -> Self {
         Self {
             cases: Default::default(),
+            exclude: Default::default(),
             include: None,
+            rerun: None,
             default_bin: None,
             timeout: Default::default(),
             env: Default::default(),
+            literate: Default::default(),
+            keep_sandbox: Default::default(),
+            shard: None,
         }
     }
 
@@ -34,10 +46,19 @@ impl RunnerSpec {
         });
     }
 
+    pub(crate) fn exclude(&mut self, glob: &std::path::Path) {
+        self.exclude.push(glob.into());
+    }
+
     pub(crate) fn include(&mut self, include: Option<Vec<String>>) {
         self.include = include;
     }
 
+    /// Restrict to `paths`, unless empty
+    pub(crate) fn rerun(&mut self, paths: std::collections::BTreeSet<std::path::PathBuf>) {
+        self.rerun = (!paths.is_empty()).then_some(paths);
+    }
+
     pub(crate) fn default_bin(&mut self, bin: Option<crate::schema::Bin>) {
         self.default_bin = bin;
     }
@@ -50,30 +71,81 @@ impl RunnerSpec {
         self.env.add.insert(key.into(), value.into());
     }
 
+    pub(crate) fn hermetic(&mut self, yes: bool) {
+        self.env.hermetic = Some(yes);
+    }
+
+    pub(crate) fn keep_sandbox(&mut self, keep: crate::KeepSandbox) {
+        self.keep_sandbox = keep;
+    }
+
+    pub(crate) fn shard(&mut self, shard: Result<Shard, crate::Error>) {
+        self.shard = Some(shard);
+    }
+
+    pub(crate) fn has_shard(&self) -> bool {
+        self.shard.is_some()
+    }
+
+    pub(crate) fn literate(&mut self) -> &mut crate::schema::Literate {
+        &mut self.literate
+    }
+
+    /// Where failures are recorded for `TRYCMD_RERUN=failed`
+    pub(crate) fn last_failed(&self) -> Option<crate::LastFailed> {
+        let globs = self
+            .cases
+            .iter()
+            .map(|spec| spec.glob.as_path())
+            .collect::<Vec<_>>();
+        crate::LastFailed::new(&globs)
+    }
+
     pub(crate) fn prepare(&mut self) -> crate::Runner {
         let mut runner = crate::Runner::new();
 
+        let shard = match self.shard.clone().transpose() {
+            Ok(shard) => shard,
+            Err(err) => {
+                // Rather than running every case on every machine
+                runner.case(crate::Case::with_error("shard".into(), err));
+                return runner;
+            }
+        };
+        runner.last_failed(self.last_failed());
+
         // Both sort and let the last writer win to allow overriding specific cases within a glob
         let mut cases: BTreeMap<std::path::PathBuf, crate::Case> = BTreeMap::new();
 
         for spec in &self.cases {
-            if let Some(glob) = get_glob(&spec.glob) {
+            let (glob, line) = match spec.glob.to_str() {
+                Some(glob) => {
+                    let (glob, line) = split_line(glob);
+                    (std::path::Path::new(glob), line)
+                }
+                None => (spec.glob.as_path(), None),
+            };
+            if let Some(glob) = get_glob(glob) {
                 match ::glob::glob(glob) {
                     Ok(paths) => {
                         for path in paths {
                             match path {
-                                Ok(path) => {
-                                    cases.insert(
-                                        path.clone(),
-                                        crate::Case {
+                                Ok(path) if path.is_dir() => {
+                                    // Other directories, like `*.in/`, aren't cases
+                                    if let Some(path) = crate::schema::case_file(&path) {
+                                        let layout = crate::schema::Layout::Dir;
+                                        self.insert_case(
+                                            &mut cases,
                                             path,
-                                            expected: spec.expected,
-                                            default_bin: self.default_bin.clone(),
-                                            timeout: self.timeout,
-                                            env: self.env.clone(),
-                                            error: None,
-                                        },
-                                    );
+                                            layout,
+                                            spec.expected,
+                                            line,
+                                        );
+                                    }
+                                }
+                                Ok(path) => {
+                                    let layout = crate::schema::Layout::Files;
+                                    self.insert_case(&mut cases, path, layout, spec.expected, line);
                                 }
                                 Err(err) => {
                                     let path = err.path().to_owned();
@@ -91,36 +163,149 @@ impl RunnerSpec {
                         );
                     }
                 }
+            } else if glob.is_dir() {
+                match crate::schema::case_file(glob) {
+                    Some(path) => {
+                        let layout = crate::schema::Layout::Dir;
+                        self.insert_case(&mut cases, path, layout, spec.expected, line);
+                    }
+                    None => {
+                        let err = crate::Error::new(format!(
+                            "No `cmd.toml` or `case.trycmd` in {}",
+                            glob.display()
+                        ));
+                        cases.insert(glob.into(), crate::Case::with_error(glob.into(), err));
+                    }
+                }
             } else {
-                let path = spec.glob.as_path();
-                cases.insert(
-                    path.into(),
-                    crate::Case {
-                        path: path.into(),
-                        expected: spec.expected,
-                        default_bin: self.default_bin.clone(),
-                        timeout: self.timeout,
-                        env: self.env.clone(),
-                        error: None,
-                    },
-                );
+                let layout = crate::schema::Layout::Files;
+                self.insert_case(&mut cases, glob.into(), layout, spec.expected, line);
             }
         }
 
-        for case in cases.into_values() {
-            if self.is_included(&case) {
+        let mut exclude = Vec::new();
+        for glob in &self.exclude {
+            match ::glob::Pattern::new(&glob.to_string_lossy()) {
+                Ok(pattern) => exclude.push(pattern),
+                Err(err) => {
+                    let err = crate::Error::new(err.to_string());
+                    runner.case(crate::Case::with_error(glob.clone(), err));
+                }
+            }
+        }
+        let mut ignores = BTreeMap::new();
+        let mut selected = 0;
+        let mut sharded = 0;
+
+        for mut case in cases.into_values() {
+            if !self.is_included(&mut case) {
+                continue;
+            }
+            if exclude.iter().any(|p| p.matches_path(&case.path)) {
+                continue;
+            }
+            match is_ignored(&case.path, case.layout, &mut ignores) {
+                Ok(false) => {}
+                Ok(true) => continue,
+                Err(err) => {
+                    runner.case(crate::Case::with_error(case.path, err));
+                    continue;
+                }
+            }
+            selected += 1;
+            if let Some(shard) = shard {
+                if !shard.contains(&case.path) {
+                    continue;
+                }
+            }
+            sharded += 1;
+            if case.error.is_some() || case.expected == Some(crate::schema::CommandStatus::Skipped)
+            {
                 runner.case(case);
+                continue;
+            }
+            match crate::schema::TryCmd::load_matrix(&case.path) {
+                Ok(matrix) => {
+                    for combination in combinations(&matrix) {
+                        runner.case(crate::Case {
+                            matrix: combination,
+                            ..case.clone()
+                        });
+                    }
+                }
+                Err(err) => runner.case(crate::Case::with_error(case.path, err)),
             }
         }
+        if let Some(shard) = shard {
+            runner.shard(shard, sharded, selected);
+        }
 
         runner
     }
 
-    fn is_included(&self, case: &crate::Case) -> bool {
+    /// Add `path`, or when `line` is set, only its code block containing `line`
+    fn insert_case(
+        &self,
+        cases: &mut BTreeMap<std::path::PathBuf, crate::Case>,
+        path: std::path::PathBuf,
+        layout: crate::schema::Layout,
+        expected: Option<crate::schema::CommandStatus>,
+        line: Option<usize>,
+    ) {
+        let new_case = |path: std::path::PathBuf, expected| crate::Case {
+            path,
+            layout,
+            expected,
+            default_bin: self.default_bin.clone(),
+            timeout: self.timeout,
+            env: self.env.clone(),
+            literate: self.literate.clone(),
+            matrix: Default::default(),
+            lines: Default::default(),
+            line_expected: Default::default(),
+            keep_sandbox: self.keep_sandbox,
+            error: None,
+        };
+        match line {
+            Some(line) => {
+                let case = cases
+                    .entry(path.clone())
+                    .or_insert_with(|| new_case(path, None));
+                match expected {
+                    Some(expected) => {
+                        case.line_expected.insert(line, expected);
+                    }
+                    None => case.lines.push(line),
+                }
+            }
+            None => {
+                cases.insert(path.clone(), new_case(path, expected));
+            }
+        }
+    }
+
+    /// Whether `case` is selected, restricting it to the lines of `trycmd=<name>:<line>` filters
+    fn is_included(&self, case: &mut crate::Case) -> bool {
+        if let Some(rerun) = &self.rerun {
+            if !rerun.contains(&case.path) {
+                return false;
+            }
+        }
         if let Some(include) = self.include.as_deref() {
-            include
-                .iter()
-                .any(|i| case.path.to_string_lossy().contains(i))
+            let path = case.path.to_string_lossy().into_owned();
+            let mut whole = false;
+            let mut lines = Vec::new();
+            for (name, line) in include.iter().map(|i| split_line(i)) {
+                match (path.contains(name), line) {
+                    (true, Some(line)) => lines.push(line),
+                    (true, None) => whole = true,
+                    (false, _) => {}
+                }
+            }
+            if !whole {
+                case.lines.extend(&lines);
+            }
+            whole || !lines.is_empty()
         } else {
             true
         }
@@ -139,6 +324,98 @@ struct CaseSpec {
     expected: Option<crate::schema::CommandStatus>,
 }
 
+/// Part of the cases, for splitting them across machines with `TRYCMD_SHARD=<index>/<total>`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Shard {
+    /// Starting from 1
+    index: usize,
+    total: usize,
+}
+
+impl Shard {
+    pub(crate) fn new(index: usize, total: usize) -> Result<Self, crate::Error> {
+        if index == 0 || total < index {
+            return Err(format!(
+                "Shard {}/{} must have an index from 1 to the total",
+                index, total
+            )
+            .into());
+        }
+        Ok(Self { index, total })
+    }
+
+    /// Parse `<index>/<total>`
+    pub(crate) fn parse(s: &str) -> Result<Self, crate::Error> {
+        let (index, total) = s
+            .split_once('/')
+            .and_then(|(index, total)| Some((index.parse().ok()?, total.parse().ok()?)))
+            .ok_or_else(|| format!("Shard `{}` must be `<index>/<total>`", s))?;
+        Self::new(index, total)
+    }
+
+    /// Whether this shard runs `path`
+    ///
+    /// Paths are hashed with FNV-1a, rather than `DefaultHasher`, so every machine agrees.
+    fn contains(&self, path: &std::path::Path) -> bool {
+        let path = path.to_string_lossy().replace('\\', "/");
+        fnv1a(path.as_bytes()) % self.total as u64 == (self.index - 1) as u64
+    }
+}
+
+/// A hash that is the same across machines and Rust versions
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+impl std::fmt::Display for Shard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.total)
+    }
+}
+
+/// Whether `.trycmdignore` in the case's directory matches it, using gitignore syntax
+fn is_ignored(
+    path: &std::path::Path,
+    layout: crate::schema::Layout,
+    ignores: &mut BTreeMap<std::path::PathBuf, ignore::gitignore::Gitignore>,
+) -> Result<bool, crate::Error> {
+    // Case directories are matched as a directory in their parent
+    let path = match path.parent() {
+        Some(case_dir) if layout == crate::schema::Layout::Dir => case_dir,
+        _ => path,
+    };
+    let dir = path.parent().unwrap_or_else(|| std::path::Path::new(""));
+    let ignore = match ignores.get(dir) {
+        Some(ignore) => ignore,
+        None => {
+            let ignore_path = dir.join(".trycmdignore");
+            let ignore = if ignore_path.exists() {
+                let (ignore, err) = ignore::gitignore::Gitignore::new(&ignore_path);
+                if let Some(err) = err {
+                    return Err(format!("Failed to read {}: {}", ignore_path.display(), err).into());
+                }
+                ignore
+            } else {
+                ignore::gitignore::Gitignore::empty()
+            };
+            ignores.entry(dir.to_owned()).or_insert(ignore)
+        }
+    };
+    Ok(ignore.matched(path, path.is_dir()).is_ignore())
+}
+
+/// Split `<path>:<line>`
+fn split_line(s: &str) -> (&str, Option<usize>) {
+    s.rsplit_once(':')
+        .and_then(|(path, line)| Some((path, Some(line.parse().ok()?))))
+        .unwrap_or((s, None))
+}
+
 fn get_glob(path: &std::path::Path) -> Option<&str> {
     if let Some(utf8) = path.to_str() {
         if utf8.contains('*') {
@@ -148,3 +425,128 @@ fn get_glob(path: &std::path::Path) -> Option<&str> {
 
     None
 }
+
+/// Every combination of the `matrix` values, or one empty combination
+fn combinations(matrix: &BTreeMap<String, Vec<String>>) -> Vec<BTreeMap<String, String>> {
+    let mut combinations = vec![BTreeMap::new()];
+    for (name, values) in matrix {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|combination| {
+                values.iter().map(move |value| {
+                    let mut combination = combination.clone();
+                    combination.insert(name.clone(), value.clone());
+                    combination
+                })
+            })
+            .collect();
+    }
+    combinations
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn combinations_cartesian() {
+        let matrix = BTreeMap::from([
+            (
+                "format".to_owned(),
+                vec!["json".to_owned(), "yaml".to_owned()],
+            ),
+            ("level".to_owned(), vec!["1".to_owned(), "2".to_owned()]),
+        ]);
+        let actual = combinations(&matrix)
+            .into_iter()
+            .map(|c| {
+                c.into_iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            actual,
+            vec![
+                vec!["format=json", "level=1"],
+                vec!["format=json", "level=2"],
+                vec!["format=yaml", "level=1"],
+                vec!["format=yaml", "level=2"],
+            ]
+        );
+    }
+
+    #[test]
+    fn combinations_empty() {
+        assert_eq!(combinations(&BTreeMap::new()), vec![BTreeMap::new()]);
+    }
+
+    #[test]
+    fn rerun_failed() {
+        let case = |path: &str| crate::Case::with_error(path.into(), crate::Error::new("unused"));
+        let mut spec = RunnerSpec::new();
+        spec.rerun(Default::default());
+        assert!(spec.is_included(&mut case("tests/cmd/a.toml")));
+
+        spec.rerun(IntoIterator::into_iter(["tests/cmd/b.toml".into()]).collect());
+        assert!(!spec.is_included(&mut case("tests/cmd/a.toml")));
+        assert!(spec.is_included(&mut case("tests/cmd/b.toml")));
+    }
+
+    #[test]
+    fn include_line() {
+        let case = |path: &str| crate::Case::with_error(path.into(), crate::Error::new("unused"));
+        let mut spec = RunnerSpec::new();
+        spec.include(Some(vec!["README.md:120".into(), "README.md:5".into()]));
+        let mut readme = case("README.md");
+        assert!(spec.is_included(&mut readme));
+        assert_eq!(readme.lines, [120, 5]);
+        assert!(!spec.is_included(&mut case("tests/cmd/a.md")));
+
+        spec.include(Some(vec!["README.md:120".into(), "README".into()]));
+        let mut readme = case("README.md");
+        assert!(spec.is_included(&mut readme));
+        assert!(readme.lines.is_empty());
+    }
+
+    #[test]
+    fn shard_partitions() {
+        let paths = (0..20)
+            .map(|i| std::path::PathBuf::from(format!("tests/cmd/case-{}.toml", i)))
+            .collect::<Vec<_>>();
+        let shards = (1..=3)
+            .map(|i| Shard::new(i, 3).unwrap())
+            .collect::<Vec<_>>();
+        for path in &paths {
+            assert_eq!(shards.iter().filter(|s| s.contains(path)).count(), 1);
+        }
+        for shard in &shards {
+            assert!(
+                paths.iter().any(|p| shard.contains(p)),
+                "{} is empty",
+                shard
+            );
+        }
+        assert!(Shard::new(1, 1).unwrap().contains(&paths[0]));
+        assert_eq!(
+            shards[0].contains(std::path::Path::new("tests/cmd/a.toml")),
+            shards[0].contains(std::path::Path::new(r"tests\cmd\a.toml"))
+        );
+    }
+
+    #[test]
+    fn shard_parse() {
+        assert_eq!(Shard::parse("2/3").unwrap(), Shard::new(2, 3).unwrap());
+        assert!(Shard::parse("0/3").is_err());
+        assert!(Shard::parse("4/3").is_err());
+        assert!(Shard::parse("1/0").is_err());
+        assert!(Shard::parse("2").is_err());
+    }
+
+    #[test]
+    fn split_line_suffix() {
+        assert_eq!(split_line("README.md:120"), ("README.md", Some(120)));
+        assert_eq!(split_line("README.md"), ("README.md", None));
+        assert_eq!(split_line(r"C:\README.md"), (r"C:\README.md", None));
+    }
+}
@@ -1,7 +1,14 @@
 #[test]
 fn cli_tests() {
     let t = trycmd::TestCases::new();
-    t.case("tests/cmd/*.trycmd").case("tests/cmd/*.toml");
+    t.case("tests/cmd/*.trycmd")
+        .case("tests/cmd/*.toml")
+        .case("tests/cmd/*.rs")
+        .case("tests/cmd/*.rst")
+        .case("tests/cmd/*.adoc")
+        .case("tests/cmd/*/")
+        .fail("tests/cmd/block-status.trycmd:4")
+        .exclude("tests/cmd/excluded.*");
     #[cfg(not(feature = "schema"))]
     {
         t.skip("tests/cmd/schema.toml");
@@ -14,6 +21,45 @@ fn cli_tests() {
         t.skip("tests/cmd/normalize.toml");
         t.skip("tests/cmd/symlink.toml");
         t.skip("tests/cmd/subst-cwd.toml");
+        t.skip("tests/cmd/record.toml");
+        t.skip("tests/cmd/record-md.toml");
+        t.skip("tests/cmd/record-in.toml");
+        t.skip("tests/cmd/record-retry.trycmd");
+        t.skip("tests/cmd/convert.toml");
+        t.skip("tests/cmd/convert-front-matter.toml");
+        t.skip("tests/cmd/convert-to-front-matter.toml");
+        t.skip("tests/cmd/convert-hermetic.toml");
+        t.skip("tests/cmd/shell-syntax.trycmd");
+        t.skip("tests/cmd/pipeline.trycmd");
+        t.skip("tests/cmd/cd.trycmd");
+        t.skip("tests/cmd/step-control.toml");
+        t.skip("tests/cmd/front-matter.trycmd");
+        t.skip("tests/cmd/file-blocks.trycmd");
+        t.skip("tests/cmd/mocks.toml");
+        t.skip("tests/cmd/mocks-quoting.toml");
+        t.skip("tests/cmd/mocks-literate.trycmd");
+        t.skip("tests/cmd/hermetic.toml");
+        t.skip("tests/cmd/immutable.toml");
+        t.skip("tests/cmd/keep-sandbox.toml");
+        t.skip("tests/cmd/case-dir/");
+    }
+    #[cfg(feature = "debug")]
+    {
+        // Debug logging is mixed into the output being verified
+        t.skip("tests/cmd/step-control.toml");
+        t.skip("tests/cmd/immutable.toml");
+        t.skip("tests/cmd/keep-sandbox.toml");
+        t.skip("tests/cmd/shard.toml");
+        t.skip("tests/cmd/shard-invalid.toml");
+        t.skip("tests/cmd/record-retry.trycmd");
+    }
+    #[cfg(not(unix))]
+    {
+        t.skip("tests/cmd/mocks.toml");
+        t.skip("tests/cmd/mocks-quoting.toml");
+        t.skip("tests/cmd/mocks-literate.trycmd");
+        // `TMPDIR` is ignored
+        t.skip("tests/cmd/keep-sandbox.toml");
     }
     #[cfg(target_os = "windows")]
     {
@@ -0,0 +1,28 @@
+//! ```console
+//! $ bin-fixture
+//! ```
+
+/// Prints the `stdout` environment variable
+///
+/// ```console
+/// $ stdout=Hello bin-fixture
+/// Hello
+///
+/// $ exit=1 bin-fixture
+/// ? 1
+/// ```
+///
+/// ```console,ignore
+/// $ this-is-ignored
+/// ```
+///
+/// ```
+/// assert_eq!(1 + 1, 2);
+/// ```
+///
+/// ```console
+/// $ stdout=World bin-fixture
+/// World
+///
+/// ```
+fn main() {}